@@ -1,13 +1,21 @@
 // finisher.rs
 
-use log::{error, info};
+use std::collections::HashSet;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
+use tacc_sync::daemon::TokenPool;
+use tacc_sync::tasklog::TaskLogGuard;
 use tacc_sync::{
     boolify, clean_up_and_exit, find_json_files_in_directory, load_request_from_file,
     load_work_from_file, move_to_outbox, TaccSyncRequest
 };
+use tracing::{error, info, info_span, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use uuid::Uuid;
 
 /// the process exit code indicating successful exit
 const EXIT_SUCCESS: i32 = 0;
@@ -16,21 +24,37 @@ const EXIT_SUCCESS: i32 = 0;
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    // initialize logging
-    env_logger::init();
+    // initialize tracing: events go to stderr as before, and any event
+    // emitted inside a request's span is additionally mirrored to that
+    // request's own log file via TaskLogLayer
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(tacc_sync::tasklog::TaskLogLayer)
+        .init();
     info!("tacc-sync v{} - finisher starting", VERSION);
 
     // load configuration from environment
     let globus_dir = std::env::var("GLOBUS_DIR").expect("GLOBUS_DIR environment variable not set");
     let hpss_dir = std::env::var("HPSS_DIR").expect("HPSS_DIR environment variable not set");
     let inbox_dir = std::env::var("INBOX_DIR").expect("INBOX_DIR environment variable not set");
+    let lease_dir = std::env::var("LEASE_DIR").expect("LEASE_DIR environment variable not set");
     let outbox_dir = std::env::var("OUTBOX_DIR").expect("OUTBOX_DIR environment variable not set");
     let pid_path = std::env::var("PID_PATH").expect("PID_PATH environment variable not set");
     let quarantine_dir = std::env::var("QUARANTINE_DIR").expect("QUARANTINE_DIR environment variable not set");
     let reaper_dir = std::env::var("REAPER_DIR").expect("REAPER_DIR environment variable not set");
     let run_once_and_die = std::env::var("RUN_ONCE_AND_DIE").expect("RUN_ONCE_AND_DIE environment variable not set");
+    let status_dir = std::env::var("STATUS_DIR").expect("STATUS_DIR environment variable not set");
+    let task_log_dir = std::env::var("TASK_LOG_DIR").expect("TASK_LOG_DIR environment variable not set");
     let work_sleep_seconds = std::env::var("WORK_SLEEP_SECONDS").expect("WORK_SLEEP_SECONDS environment variable not set");
 
+    // the size of the worker pool used to check requests for in-flight work
+    // concurrently; defaults to 1 (strictly sequential) when WORKER_THREADS
+    // isn't set
+    let worker_threads = match std::env::var("WORKER_THREADS") {
+        Ok(value) => value.parse::<usize>().expect("WORKER_THREADS environment variable must be an integer").max(1),
+        Err(_) => 1,
+    };
+
     let run_once = boolify(&run_once_and_die);
     let sleep_seconds = work_sleep_seconds.parse::<u64>().expect("WORK_SLEEP_SECONDS environment variable must be an integer");
 
@@ -43,29 +67,90 @@ fn main() {
         let json_files = find_json_files_in_directory(&inbox_dir);
         let num_files = json_files.len();
 
-        // for each unit of work
-        info!("Processing {} work units", num_files);
-        for (index, json_file) in json_files.iter().enumerate() {
-            let json_file_str = json_file.as_path().display();
-            info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
-            // if we are able to load the sync request from the file
-            if let Ok(request) = load_request_from_file(json_file) {
-                // process the sync request
-                process_sync_request(
-                    json_file,
-                    &PathBuf::from(&outbox_dir),
-                    &request,
-                    &PathBuf::from(&hpss_dir),
-                    &PathBuf::from(&globus_dir),
-                    &PathBuf::from(&reaper_dir),
-                );
-            }
-            // we weren't able to load the sync request
-            else {
-                error!("Unable to load TaccSyncRequest: {}", json_file_str);
-                move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
+        // dispatch each request onto up to worker_threads worker threads; a
+        // request's readiness check never depends on another request's, so
+        // one stuck/slow directory scan doesn't hold up the rest
+        info!("Processing {} work units with up to {} in flight", num_files, worker_threads);
+        let tokens = TokenPool::new(worker_threads);
+        let completed = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for (index, json_file) in json_files.iter().enumerate() {
+                tokens.acquire();
+                let release = tokens.returns();
+                let completed = &completed;
+                let outbox_dir = &outbox_dir;
+                let hpss_dir = &hpss_dir;
+                let globus_dir = &globus_dir;
+                let reaper_dir = &reaper_dir;
+                let lease_dir = &lease_dir;
+                let status_dir = &status_dir;
+                let task_log_dir = &task_log_dir;
+                let quarantine_dir = &quarantine_dir;
+
+                scope.spawn(move || {
+                    let json_file_str = json_file.as_path().display();
+                    info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
+                    // if we are able to load the sync request from the file
+                    match load_request_from_file(json_file) {
+                        Ok(request) => {
+                            let request_id = request.request_id.to_string();
+                            let span = info_span!("sync_request", request_id = %request.request_id);
+                            let _span_guard = span.enter();
+
+                            // the syncer writes this request's log while
+                            // generating its work units, then moves it
+                            // alongside the request's JSON into whatever
+                            // directory becomes our inbox; adopt that log
+                            // file back into task_log_dir on our first look
+                            // at this request so the request's history
+                            // carries forward instead of starting fresh
+                            let log_path = TaskLogGuard::path_for(&PathBuf::from(task_log_dir), &request_id);
+                            if !log_path.exists() {
+                                let adopted_path = json_file.with_file_name(format!("{}.log", request_id));
+                                if adopted_path.exists() {
+                                    if let Err(e) = std::fs::rename(&adopted_path, &log_path) {
+                                        error!("Unable to adopt existing task log {} for request {}: {}", adopted_path.display(), request_id, e);
+                                    }
+                                }
+                            }
+                            let log_guard = TaskLogGuard::open(&PathBuf::from(task_log_dir), &request_id)
+                                .map_err(|e| error!("Unable to open per-request log file for {}: {}", request_id, e))
+                                .ok();
+
+                            // process the sync request
+                            let finished = process_sync_request(
+                                json_file,
+                                &PathBuf::from(outbox_dir),
+                                &request,
+                                &PathBuf::from(hpss_dir),
+                                &PathBuf::from(globus_dir),
+                                &PathBuf::from(reaper_dir),
+                                &PathBuf::from(status_dir),
+                                &PathBuf::from(lease_dir),
+                            );
+
+                            // drop the guard so the log file is flushed and closed before we try to move it
+                            drop(log_guard);
+
+                            // only move the log file alongside the request once the
+                            // request is actually finished; otherwise leave it where
+                            // it is so the next finisher cycle can find and adopt it
+                            if finished {
+                                TaskLogGuard::move_to(&PathBuf::from(task_log_dir), &request_id, &PathBuf::from(outbox_dir));
+                            }
+                        },
+                        // we weren't able to load the sync request
+                        Err(e) => {
+                            error!("Unable to load TaccSyncRequest: {}: {}", json_file_str, e);
+                            let _ = move_to_outbox(json_file, &PathBuf::from(quarantine_dir));
+                        },
+                    }
+                    let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                    info!("Finished {}/{}: {}", done, num_files, json_file_str);
+                    let _ = release.send(());
+                });
             }
-        }
+        });
 
         // if this was a one-shot adventure
         if run_once {
@@ -79,52 +164,103 @@ fn main() {
     }
 }
 
+/// Check whether `request`'s work has fully cleared the pipeline and, if so,
+/// move it to the outbox. Returns `true` if the request was moved (i.e. is
+/// finished), `false` if it's still in flight and will be checked again on
+/// a later cycle.
 fn process_sync_request(
     json_file: &PathBuf,        // the request file being processed
     outbox_dir: &PathBuf,       // the outbox where the request will go if no work is associated with the request
     request: &TaccSyncRequest,  // the request object
     hpss_dir: &PathBuf,         // the hpss_dir where work may be found
     globus_dir: &PathBuf,       // the globus_dir where work may be found
-    reaper_dir: &PathBuf        // the reaper_dir where work may be found
-) {
+    reaper_dir: &PathBuf,       // the reaper_dir where work may be found
+    status_dir: &PathBuf,       // where this request's progress status file lives
+    lease_dir: &PathBuf,        // where the syncer recorded which work units it generated for this request
+) -> bool {
     // determine the request_id
     info!("Checking for work with request_id = {}", request.request_id);
-    // create a vector of the directories we intend to check
-    let check_dirs = vec![hpss_dir, globus_dir, reaper_dir];
-    // for each directory that we need to check for work
-    for check_dir in check_dirs {
-        // log about the directory we're checking
-        info!("Checking for work in directory {}", check_dir.display());
-        // find all the work files in the directory
-        let dir_path = check_dir.to_string_lossy().to_string();
-        let work_files = find_json_files_in_directory(&dir_path);
-        // for each work file
-        for work_file in work_files {
-            // if we are able to load the work
-            if let Ok(work) = load_work_from_file(&work_file) {
-                // if the request id from the work matches the request id from the request
-                if work.request_id == request.request_id {
-                    // we're done with this request; work is in-flight, so this request
-                    // is NOT finished, and it is NOT ready to go to the finished directory
-                    info!("Work {} has request_id {}", work_file.display(), work.request_id);
-                    info!("Will NOT move the request to {}", outbox_dir.display());
-                    return
-                }
-            }
-            else {
-                // we were unable to load the work; this may have happened because another
-                // component was processing the work, finished it, and moved it downstream
-                // before we had a chance to load it. to cover this case, we'll log an error
-                // but take no specific action. we'll check this request on another cycle
-                error!("Unable to load work {}", work_file.display());
-                info!("Will NOT move the request to {}", outbox_dir.display());
-                return
-            }
-        }
+
+    // the lease tells us which work_ids actually belong to this request, so
+    // an unreadable file elsewhere in these directories (some other
+    // request's file, caught mid-write) doesn't falsely block us
+    let leased_work_ids: HashSet<Uuid> = tacc_sync::lease::read(lease_dir, request.request_id)
+        .unwrap_or_else(|e| {
+            error!("Unable to read lease for request {}: {}; assuming no leased work_ids", request.request_id, e);
+            tacc_sync::lease::Lease::default()
+        })
+        .groups
+        .iter()
+        .map(|g| g.work_id)
+        .collect();
+
+    // count the in-flight work units for this request in each directory; we
+    // scan every directory (rather than stopping at the first match) so the
+    // status record always reflects where the work actually is
+    let span = info_span!("count_in_flight_work", request_id = %request.request_id, phase = "checking_in_flight");
+    let hpss_in_flight = span.in_scope(|| count_in_flight_work(hpss_dir, request.request_id, &leased_work_ids));
+    let globus_in_flight = span.in_scope(|| count_in_flight_work(globus_dir, request.request_id, &leased_work_ids));
+    let reaper_in_flight = span.in_scope(|| count_in_flight_work(reaper_dir, request.request_id, &leased_work_ids));
+
+    if let Err(e) = tacc_sync::status::set_in_flight(status_dir, request.request_id, hpss_in_flight, globus_in_flight, reaper_in_flight) {
+        error!("Unable to record in-flight status for request {}: {}", request.request_id, e);
+    }
+
+    if hpss_in_flight + globus_in_flight + reaper_in_flight > 0 {
+        info!("Will NOT move the request to {}", outbox_dir.display());
+        return false
     }
+
     // we've checked every work unit in all of the directories
     // none of them contain the request id of this request
     // the request IS finished, and IS ready to move to the finished directory
     info!("Work directories have been exhausted. Request {} is completely finished.", request.request_id);
-    move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
+    if let Err(e) = tacc_sync::status::finish(status_dir, request.request_id) {
+        error!("Unable to record finished status for request {}: {}", request.request_id, e);
+    }
+    if let Err(e) = tacc_sync::lease::clear(lease_dir, request.request_id) {
+        error!("Unable to clear lease for request {}: {}", request.request_id, e);
+    }
+    let _ = move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
+    true
+}
+
+/// Count how many work units in `check_dir` belong to `request_id`.
+///
+/// A file that fails to load is ambiguous: it may be mid-write/move by
+/// another component. If its filename (a work_id) is one this request's
+/// lease says was generated, we conservatively count it as still in
+/// flight; otherwise it belongs to some other request (or is simply
+/// corrupt) and shouldn't hold this request back.
+fn count_in_flight_work(check_dir: &PathBuf, request_id: Uuid, leased_work_ids: &HashSet<Uuid>) -> usize {
+    info!("Checking for work in directory {}", check_dir.display());
+    let dir_path = check_dir.to_string_lossy().to_string();
+    let work_files = find_json_files_in_directory(&dir_path);
+
+    let mut in_flight = 0;
+    for work_file in work_files {
+        match load_work_from_file(&work_file) {
+            Ok(work) => {
+                if work.request_id == request_id {
+                    info!("Work {} has request_id {}", work_file.display(), work.request_id);
+                    in_flight += 1;
+                }
+            },
+            Err(e) => {
+                let leased = work_file
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| Uuid::parse_str(s).ok())
+                    .map(|work_id| leased_work_ids.contains(&work_id))
+                    .unwrap_or(false);
+                if leased {
+                    warn!("Unable to load work {}, but it is one of request {}'s own leased units; treating as still in flight: {}", work_file.display(), request_id, e);
+                    in_flight += 1;
+                } else {
+                    warn!("Unable to load work {}; ignoring for request {} since it is not one of its leased units: {}", work_file.display(), request_id, e);
+                }
+            },
+        }
+    }
+    in_flight
 }
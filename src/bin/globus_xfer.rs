@@ -1,22 +1,31 @@
 // globus_xfer.rs
 
 use anyhow::Result;
-use log::{debug, error, info};
+use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::thread::sleep;
-use std::time::Duration;
-use tacc_sync::{
-    boolify, clean_up_and_exit, find_json_files_in_directory,
-    load_work_from_file, move_to_outbox, TaccSyncFile, TaccSyncWork
-};
+use std::sync::Mutex;
+use std::thread;
+use tacc_sync::daemon::{run_daemon, DaemonConfig, WorkOutcome, Worker};
+use tacc_sync::{atomic_write_json, TaccSyncFile, TaccSyncWork, WorkPhase};
+use tracing::{debug, error, info};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
 
-/// the process exit code indicating successful exit
-const EXIT_SUCCESS: i32 = 0;
+/// the default Globus Auth token endpoint
+const DEFAULT_GLOBUS_TOKEN_URL: &'static str = "https://auth.globus.org/v2/oauth2/token";
+
+/// the default Globus Transfer REST API base URL
+const DEFAULT_GLOBUS_TRANSFER_API_BASE: &'static str = "https://transfer.api.globus.org/v0.10";
+
+/// the delay before the first retry of a retryable file failure
+const INITIAL_RETRY_DELAY_SECONDS: u64 = 30;
+
+/// the cap on the exponential backoff delay between retries
+const MAX_RETRY_DELAY_SECONDS: u64 = 1800;
 
 /// the version of the package being compiled
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -29,9 +38,253 @@ pub struct GlobusXferContext {
     pub globus_source_endpoint: String,
     pub hpss_base_path: String,
     pub inbox_dir: String,
+    /// where a finished work unit's JSON (and progress sidecar) is moved
+    pub outbox_dir: PathBuf,
+    /// where a failed work unit's JSON (and progress sidecar) is moved
+    pub quarantine_dir: PathBuf,
     pub semaphore_dir: String,
     pub tacc_base_path: String,
     pub transfer_dir: String,
+    /// where per-work-unit log files are written while a unit is in flight
+    pub task_log_dir: PathBuf,
+    /// where the task registry's active/archive files are kept
+    pub registry_dir: PathBuf,
+    /// the maximum number of files within one work unit to hand to
+    /// `execute_globus_transfer`/`execute_globus_task_show` at once
+    pub max_file_concurrency: usize,
+    /// how to actually talk to Globus: the `globus` CLI, or the REST API directly
+    pub backend: GlobusBackend,
+    /// how many times a file's transfer is retried on a retryable failure
+    /// before the work unit is quarantined
+    pub max_retries: u32,
+}
+
+/// `run_daemon` owns the poll/dispatch/routing loop and calls `process` once
+/// per work unit (on its own worker thread, inside the span/task-log guard
+/// `daemon::process_one` already opens); this just adds the registry
+/// start/finish-task bookkeeping and progress-sidecar move that are specific
+/// to this stage.
+impl Worker for GlobusXferContext {
+    fn process(&self, work: &mut TaccSyncWork, json_file: &Path) -> WorkOutcome {
+        // a multi-cycle transfer already has an active record from the poll
+        // cycle that called start_task; reuse it instead of appending a
+        // duplicate one every time this work unit comes back around
+        let active_task = match tacc_sync::registry::find_active(&self.registry_dir, work.work_id) {
+            Ok(Some(existing)) => Some(existing),
+            Ok(None) => tacc_sync::registry::start_task(&self.registry_dir, work.work_id, work.request_id, &work.tape)
+                .map_err(|e| error!("Unable to record task start in registry: {}", e))
+                .ok(),
+            Err(e) => {
+                error!("Unable to look up active task for {}: {}", work.work_id, e);
+                None
+            },
+        };
+
+        let result = process_work(self, work, active_task.as_ref().map(|a| &a.id));
+
+        let warning_count = tacc_sync::tasklog::warning_count();
+        if warning_count > 0 {
+            info!("Work unit {} completed with {} warning(s)/error(s) logged", work.work_id, warning_count);
+        }
+
+        // Ok(false) means the transfer is still in flight and this unit
+        // will be re-dispatched next cycle under the same active record;
+        // only close it out on a terminal outcome (done or quarantined),
+        // otherwise every poll of a multi-cycle transfer would archive a
+        // fresh "finished" record and erase it from `active` mid-transfer
+        if !matches!(result, Ok(false)) {
+            let status = match &result {
+                Err(_) => tacc_sync::registry::TaskStatus::Quarantined,
+                Ok(_) if warning_count > 0 => tacc_sync::registry::TaskStatus::Warning,
+                Ok(_) => tacc_sync::registry::TaskStatus::Ok,
+            };
+            if let Some(active_task) = &active_task {
+                if let Err(e) = tacc_sync::registry::finish_task(&self.registry_dir, active_task, status) {
+                    error!("Unable to record task completion in registry: {}", e);
+                }
+            }
+        }
+
+        let work_id = work.work_id.to_string();
+        match result {
+            Err(e) => {
+                error!("Error while processing work. Error was: {}", e);
+                move_progress_sidecar(Path::new(&self.inbox_dir), &work_id, &self.quarantine_dir);
+                WorkOutcome::Quarantine
+            },
+            Ok(true) => {
+                info!("Transfers complete. Will move work unit to outbox.");
+                if let Err(e) = atomic_write_json(work, json_file) {
+                    error!("Unable to checkpoint Transferred phase for {}: {}", work.work_id, e);
+                }
+                move_progress_sidecar(Path::new(&self.inbox_dir), &work_id, &self.outbox_dir);
+                WorkOutcome::Advance
+            },
+            Ok(false) => WorkOutcome::Retry,
+        }
+    }
+}
+
+/// GlobusBackend selects how `execute_globus_transfer`/`execute_globus_task_show`
+/// talk to Globus. `Cli` shells out to the `globus` binary, which requires it
+/// to be installed and a human to have already run `globus login`. `Rest`
+/// calls the Transfer REST API directly over HTTPS, which only needs an
+/// OAuth2 client id/secret and refresh token and so can run unattended. Selected at startup via
+/// the GLOBUS_BACKEND env var ("cli", the default, or "rest").
+pub enum GlobusBackend {
+    Cli,
+    Rest(RestClient),
+}
+
+/// A lazily-fetched, auto-refreshing OAuth2 access token for the Globus
+/// Transfer API, following the same lazy token-provider pattern used by
+/// cloud storage clients that cache a credential and only refresh it once
+/// it's actually rejected: callers ask for `access_token`, and only pay the
+/// cost of a refresh the first time or after a 401.
+pub struct GlobusTokenProvider {
+    client_id: String,
+    client_secret: String,
+    refresh_token: String,
+    token_url: String,
+    cached: Mutex<Option<String>>,
+}
+
+impl GlobusTokenProvider {
+    pub fn new(client_id: String, client_secret: String, refresh_token: String, token_url: String) -> Self {
+        GlobusTokenProvider { client_id, client_secret, refresh_token, token_url, cached: Mutex::new(None) }
+    }
+
+    /// Return a cached access token if we have one, otherwise fetch a fresh one.
+    fn access_token(&self, http: &Client) -> Result<String> {
+        if let Some(token) = self.cached.lock().expect("token cache lock poisoned").clone() {
+            return Ok(token);
+        }
+        self.refresh(http)
+    }
+
+    /// Unconditionally fetch a fresh access token and cache it, e.g. after
+    /// the cached one came back 401.
+    fn refresh(&self, http: &Client) -> Result<String> {
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+        }
+
+        info!("Refreshing Globus Transfer API access token");
+        let response: TokenResponse = http
+            .post(&self.token_url)
+            .form(&[
+                ("client_id", self.client_id.as_str()),
+                ("client_secret", self.client_secret.as_str()),
+                ("refresh_token", self.refresh_token.as_str()),
+                ("grant_type", "refresh_token"),
+            ])
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        *self.cached.lock().expect("token cache lock poisoned") = Some(response.access_token.clone());
+        Ok(response.access_token)
+    }
+}
+
+/// RestClient talks to the Globus Transfer REST API directly, in place of
+/// shelling out to the `globus` CLI.
+pub struct RestClient {
+    http: Client,
+    tokens: GlobusTokenProvider,
+    transfer_api_base: String,
+}
+
+impl RestClient {
+    pub fn new(tokens: GlobusTokenProvider, transfer_api_base: String) -> Self {
+        RestClient { http: Client::new(), tokens, transfer_api_base }
+    }
+
+    /// Send a request built by `build_request`, retrying exactly once with
+    /// a freshly refreshed token if the first attempt comes back 401 --
+    /// the cached access token may have simply expired since it was issued.
+    fn send_with_auth(
+        &self,
+        build_request: impl Fn(&Client, &str) -> reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::blocking::Response> {
+        let token = self.tokens.access_token(&self.http)?;
+        let response = build_request(&self.http, &token).send()?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            let token = self.tokens.refresh(&self.http)?;
+            return Ok(build_request(&self.http, &token).send()?.error_for_status()?);
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    /// `GET /task/{task_id}`
+    pub fn task_show(&self, task_id: Uuid) -> Result<GlobusTask> {
+        let url = format!("{}/task/{}", self.transfer_api_base, task_id);
+        let response = self.send_with_auth(|http, token| http.get(&url).bearer_auth(token))?;
+        Ok(response.json()?)
+    }
+
+    /// `GET /submission_id` followed by `POST /transfer`, preserving the
+    /// same `--sync-level mtime`/`--preserve-mtime`/`--verify-checksum`
+    /// semantics the CLI path uses as request-body fields.
+    pub fn submit_transfer(&self, source_endpoint: &str, source_path: &Path, dest_endpoint: &str, dest_path: &Path) -> Result<TransferResult> {
+        #[derive(Deserialize)]
+        struct SubmissionIdResponse {
+            value: Uuid,
+        }
+
+        let submission_id_url = format!("{}/submission_id", self.transfer_api_base);
+        let submission_id_response = self.send_with_auth(|http, token| http.get(&submission_id_url).bearer_auth(token))?;
+        let submission_id: SubmissionIdResponse = submission_id_response.json()?;
+
+        let body = serde_json::json!({
+            "DATA_TYPE": "transfer",
+            "submission_id": submission_id.value,
+            "source_endpoint": source_endpoint,
+            "destination_endpoint": dest_endpoint,
+            "sync_level": 2, // "mtime": sync if source mtime is newer than destination
+            "preserve_timestamp": true,
+            "verify_checksum": true,
+            "DATA": [{
+                "DATA_TYPE": "transfer_item",
+                "source_path": source_path.display().to_string(),
+                "destination_path": dest_path.display().to_string(),
+            }],
+        });
+
+        let transfer_url = format!("{}/transfer", self.transfer_api_base);
+        let response = self.send_with_auth(|http, token| http.post(&transfer_url).bearer_auth(token).json(&body))?;
+        Ok(response.json()?)
+    }
+
+    /// `POST /endpoint/{endpoint_id}/autoactivate`, the REST equivalent of
+    /// `globus endpoint activate`.
+    pub fn autoactivate(&self, endpoint_id: &str) -> Result<()> {
+        let url = format!("{}/endpoint/{}/autoactivate", self.transfer_api_base, endpoint_id);
+        self.send_with_auth(|http, token| http.post(&url).bearer_auth(token))?;
+        Ok(())
+    }
+}
+
+/// Build the `GlobusBackend` selected by the GLOBUS_BACKEND env var
+/// ("cli", the default, or "rest"). The "rest" backend additionally
+/// requires GLOBUS_CLIENT_ID/GLOBUS_CLIENT_SECRET/GLOBUS_REFRESH_TOKEN to
+/// be set, and honors the optional GLOBUS_TOKEN_URL/GLOBUS_TRANSFER_API_BASE
+/// overrides.
+fn globus_backend_from_env() -> GlobusBackend {
+    match std::env::var("GLOBUS_BACKEND").unwrap_or_else(|_| "cli".to_string()).as_str() {
+        "rest" => {
+            let client_id = std::env::var("GLOBUS_CLIENT_ID").expect("GLOBUS_CLIENT_ID environment variable not set");
+            let client_secret = std::env::var("GLOBUS_CLIENT_SECRET").expect("GLOBUS_CLIENT_SECRET environment variable not set");
+            let refresh_token = std::env::var("GLOBUS_REFRESH_TOKEN").expect("GLOBUS_REFRESH_TOKEN environment variable not set");
+            let token_url = std::env::var("GLOBUS_TOKEN_URL").unwrap_or_else(|_| DEFAULT_GLOBUS_TOKEN_URL.to_string());
+            let transfer_api_base = std::env::var("GLOBUS_TRANSFER_API_BASE").unwrap_or_else(|_| DEFAULT_GLOBUS_TRANSFER_API_BASE.to_string());
+
+            let tokens = GlobusTokenProvider::new(client_id, client_secret, refresh_token, token_url);
+            GlobusBackend::Rest(RestClient::new(tokens, transfer_api_base))
+        },
+        _ => GlobusBackend::Cli,
+    }
 }
 
 /// GlobusTask represents the task metdata returned by the Globus CLI
@@ -49,6 +302,22 @@ pub struct GlobusTask {
     /// "FAILED"
     ///     The task or one of its subtasks failed, expired, or was canceled.
     pub status: String,
+    /// a more specific reason code when `status` is "FAILED" or "INACTIVE",
+    /// e.g. "ENDPOINT_ERROR" or "PERMISSION_DENIED"
+    #[serde(default)]
+    pub nice_status: Option<String>,
+    /// bytes transferred so far for this task
+    #[serde(default)]
+    pub bytes_transferred: u64,
+    /// total number of files this task is transferring
+    #[serde(default)]
+    pub files: u64,
+    /// number of files this task has finished transferring
+    #[serde(default)]
+    pub files_transferred: u64,
+    /// Globus's current estimate of this task's transfer rate, in bytes/second
+    #[serde(default)]
+    pub effective_bytes_per_second: u64,
 }
 
 /// GlobusTransferCreated represents the result of the globus transfer command
@@ -69,11 +338,67 @@ pub struct TransferResult {
 struct TransferUpdate {
     pub finished: bool,
     pub updated: bool,
+    /// bytes transferred so far for this file's Globus task, if known
+    pub bytes_transferred: u64,
+    /// Globus's current transfer rate estimate for this file's task, in bytes/second
+    pub effective_bytes_per_second: u64,
+}
+
+/// FileProgress aggregates the per-file results of `process_files_concurrently`
+/// across every file in a work unit.
+struct FileProgress {
+    finished_count: usize,
+    update_count: usize,
+    bytes_transferred: u64,
+    effective_bytes_per_second: u64,
+}
+
+/// ProgressReport is the `{work_id}.progress.json` sidecar written into the
+/// inbox directory alongside a work unit's own JSON, so external dashboards
+/// can poll transfer progress without parsing logs. `event` follows a
+/// begin/report/end lifecycle: "progress" every cycle a unit isn't done yet,
+/// "end" the cycle all its files finish and it moves on to the outbox. A
+/// unit sent to quarantine gets no report that cycle, so its sidecar keeps
+/// the last "progress" report.
+#[derive(Debug, Serialize)]
+struct ProgressReport {
+    work_id: Uuid,
+    tape: String,
+    event: &'static str,
+    files_total: usize,
+    files_transferred: usize,
+    total_bytes: u64,
+    bytes_transferred: u64,
+    percent_complete: f64,
+    bytes_per_second: u64,
+    eta_seconds: Option<u64>,
+}
+
+/// The path the progress sidecar for `work_id` is written to/read from
+/// within `inbox_dir`.
+fn progress_path(inbox_dir: &Path, work_id: &str) -> PathBuf {
+    inbox_dir.join(format!("{}.progress.json", work_id))
+}
+
+/// Move a work unit's progress sidecar, if one was written, alongside its
+/// JSON into `dest_dir` so its last report travels with the unit.
+fn move_progress_sidecar(inbox_dir: &Path, work_id: &str, dest_dir: &Path) {
+    let path = progress_path(inbox_dir, work_id);
+    if path.exists() {
+        if let Err(e) = tacc_sync::durable_move(&path, dest_dir) {
+            error!("Unable to move progress sidecar {} to {}: {}", path.display(), dest_dir.display(), e);
+        }
+    }
 }
 
 fn main() {
-    // initialize logging
-    env_logger::init();
+    // initialize tracing: events go to stderr as before, and any event
+    // emitted inside a work-unit span is additionally mirrored to that
+    // unit's own log file via TaskLogLayer
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(tacc_sync::tasklog::TaskLogLayer)
+        .init();
     info!("tacc-sync v{} - globus_xfer starting", VERSION);
 
     // load transfer configuration from environment
@@ -81,19 +406,24 @@ fn main() {
     let globus_source_endpoint = std::env::var("GLOBUS_SOURCE_ENDPOINT").expect("GLOBUS_SOURCE_ENDPOINT environment variable not set");
     let hpss_base_path = std::env::var("HPSS_BASE_PATH").expect("HPSS_BASE_PATH environment variable not set");
     let tacc_base_path = std::env::var("TACC_BASE_PATH").expect("TACC_BASE_PATH environment variable not set");
-
-    // load configuration from environment
-    let inbox_dir = std::env::var("INBOX_DIR").expect("INBOX_DIR environment variable not set");
-    let outbox_dir = std::env::var("OUTBOX_DIR").expect("OUTBOX_DIR environment variable not set");
-    let pid_path = std::env::var("PID_PATH").expect("PID_PATH environment variable not set");
-    let quarantine_dir = std::env::var("QUARANTINE_DIR").expect("QUARANTINE_DIR environment variable not set");
-    let run_once_and_die = std::env::var("RUN_ONCE_AND_DIE").expect("RUN_ONCE_AND_DIE environment variable not set");
     let semaphore_dir = std::env::var("SEMAPHORE_DIR").expect("SEMAPHORE_DIR environment variable not set");
     let transfer_dir = std::env::var("TRANSFER_DIR").expect("TRANSFER_DIR environment variable not set");
-    let work_sleep_seconds = std::env::var("WORK_SLEEP_SECONDS").expect("WORK_SLEEP_SECONDS environment variable not set");
+    let task_registry_dir = std::env::var("TASK_REGISTRY_DIR").expect("TASK_REGISTRY_DIR environment variable not set");
+
+    // how many files within one work unit to hand to execute_globus_transfer/
+    // execute_globus_task_show at once; how many work units run concurrently
+    // is governed by DaemonConfig's MAX_CONCURRENCY below
+    let max_file_concurrency = match std::env::var("MAX_FILE_CONCURRENCY") {
+        Ok(v) => v.parse::<usize>().expect("MAX_FILE_CONCURRENCY environment variable must be an integer"),
+        Err(_) => 1,
+    };
+    let max_retries = match std::env::var("MAX_RETRIES") {
+        Ok(v) => v.parse::<u32>().expect("MAX_RETRIES environment variable must be an integer"),
+        Err(_) => 5,
+    };
 
-    let run_once = boolify(&run_once_and_die);
-    let sleep_seconds = work_sleep_seconds.parse::<u64>().expect("WORK_SLEEP_SECONDS environment variable must be an integer");
+    // load the shared poll/dispatch/routing configuration
+    let config = DaemonConfig::from_env().expect("Unable to load daemon configuration");
 
     // create the context
     let context = GlobusXferContext {
@@ -101,98 +431,62 @@ fn main() {
         globus_source_endpoint,
         hpss_base_path,
         tacc_base_path,
-        inbox_dir: inbox_dir.clone(),
+        inbox_dir: config.inbox_dir.to_string_lossy().to_string(),
+        outbox_dir: config.outbox_dir.clone(),
+        quarantine_dir: config.quarantine_dir.clone(),
         semaphore_dir,
         transfer_dir,
+        task_log_dir: config.task_log_dir.clone(),
+        registry_dir: PathBuf::from(task_registry_dir),
+        max_file_concurrency,
+        backend: globus_backend_from_env(),
+        max_retries,
     };
 
-    // run the main loop
-    loop {
-        info!("Starting work cycle");
-
-        // search the inbox for work to do
-        info!("Checking for work in inbox directory: {}", &inbox_dir);
-        let json_files = find_json_files_in_directory(&inbox_dir);
-        let num_files = json_files.len();
-
-        // for each unit of work
-        info!("Processing {} work units", num_files);
-        for (index, json_file) in json_files.iter().enumerate() {
-            let json_file_str = json_file.as_path().display();
-            info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
-            // if we are able to load the work from the file
-            if let Ok(mut work) = load_work_from_file(json_file) {
-                // process the work
-                match process_work(&context, &mut work) {
-                    Err(e) => {
-                        error!("Error while processing work. Error was: {}", e);
-                        move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
-                    },
-                    Ok(done) => {
-                        if done {
-                            info!("Transfers complete. Will move work unit to outbox.");
-                            move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
-                        }
-                    }
-                }
-            }
-            // we weren't able to load the sync request
-            else {
-                error!("Unable to load TaccSyncWork: {}", json_file_str);
-                move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
-            }
-        }
+    // a previous globus_xfer may have crashed mid-transfer, leaving entries in
+    // the active file that are no longer actually running; archive those as
+    // Interrupted so they don't look like in-progress transfers forever
+    match tacc_sync::registry::reconcile_active(&context.registry_dir) {
+        Ok(0) => {},
+        Ok(n) => info!("Reconciled {} stale active task(s) left behind by a previous run", n),
+        Err(e) => error!("Unable to reconcile task registry: {}", e),
+    }
 
-        // if this was a one-shot adventure
-        if run_once {
-            info!("RUN_ONCE_AND_DIE: {} -- globus_xfer now ending", run_once_and_die);
-            clean_up_and_exit(&pid_path, EXIT_SUCCESS);
-        }
+    // a SIGTERM/SIGINT just flips this flag; run_daemon finishes and
+    // checkpoints whatever's already in flight before it actually exits
+    let shutdown = tacc_sync::daemon::install_shutdown_flag();
 
-        // otherwise, sleep until we need to wake up again
-        info!("Sleeping for {} seconds...", sleep_seconds);
-        sleep(Duration::from_secs(sleep_seconds));
-    }
+    run_daemon(context, config, shutdown);
 }
 
 fn process_work(
     context: &GlobusXferContext,
     work: &mut TaccSyncWork,
+    active_task_id: Option<&tacc_sync::registry::TaskId>,
 ) -> Result<bool, Box<dyn Error>> {
     // log about what we're processing
     info!("Transferring files for {}: {} ({} files - {} bytes)", work.work_id, work.tape, work.files.len(), work.size);
 
-    // how many of the files have finished?
-    let mut finished_count = 0;
-
-    // how many of the files have been updated?
-    let mut update_count = 0;
-
-    // for each file in the work unit
-    for file in work.files.iter_mut() {
-        // process the file
-        let transfer_update = process_file(context, &work.work_id, file)?;
-        // if the file was finished transferring
-        if transfer_update.finished {
-            finished_count += 1;
-        }
-        // if the file was updated (i.e.: a globus_task_id was added
-        if transfer_update.updated {
-            update_count += 1;
-        }
-    }
+    // fan the files in this unit out across up to max_file_concurrency
+    // worker threads; each file's Globus transfer/poll runs independently,
+    // so there's no reason to block file N+1 behind file N's network round-trip
+    let progress = process_files_concurrently(context, &work.work_id, active_task_id, &mut work.files, context.max_file_concurrency)?;
 
     // if any of the files were updated
-    if update_count > 0 {
+    if progress.update_count > 0 {
         // rewrite the work unit with the new globus_task_id values
         let inbox_dir = PathBuf::from(&context.inbox_dir);
         rewrite_work_unit(work, &inbox_dir)?;
     }
 
     // if all of the files were finished
-    if finished_count >= work.files.len() {
+    let done = progress.finished_count >= work.files.len();
+    report_progress(context, work, &progress, done)?;
+
+    if done {
         // we're all done; log about it and tell the caller we're done
-        info!("All {} files have finished transferring. Will move to the outbox.", finished_count);
+        info!("All {} files have finished transferring. Will move to the outbox.", progress.finished_count);
+        work.phase = WorkPhase::Transferred;
         return Ok(true);
     }
 
@@ -201,9 +495,121 @@ fn process_work(
     Ok(false)
 }
 
+/// Process `files` in-place, split into up to `max_concurrency` chunks each
+/// run on its own scoped thread (disjoint mutable slices, so no `Arc`/`Mutex`
+/// is needed). Returns the aggregated finished/updated/progress counts
+/// across every chunk, or the first error encountered, in chunk order.
+fn process_files_concurrently(
+    context: &GlobusXferContext,
+    work_id: &Uuid,
+    active_task_id: Option<&tacc_sync::registry::TaskId>,
+    files: &mut [TaccSyncFile],
+    max_concurrency: usize,
+) -> Result<FileProgress, Box<dyn Error>> {
+    if files.is_empty() {
+        return Ok(FileProgress { finished_count: 0, update_count: 0, bytes_transferred: 0, effective_bytes_per_second: 0 });
+    }
+
+    let max_concurrency = max_concurrency.max(1).min(files.len());
+    let chunk_size = (files.len() + max_concurrency - 1) / max_concurrency;
+
+    let chunk_results = thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks_mut(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    let mut finished_count = 0;
+                    let mut update_count = 0;
+                    let mut bytes_transferred = 0u64;
+                    let mut effective_bytes_per_second = 0u64;
+                    for file in chunk.iter_mut() {
+                        let transfer_update = process_file(context, work_id, active_task_id, file)?;
+                        if transfer_update.finished {
+                            finished_count += 1;
+                        }
+                        if transfer_update.updated {
+                            update_count += 1;
+                        }
+                        bytes_transferred += transfer_update.bytes_transferred;
+                        effective_bytes_per_second += transfer_update.effective_bytes_per_second;
+                    }
+                    Ok::<(usize, usize, u64, u64), anyhow::Error>((finished_count, update_count, bytes_transferred, effective_bytes_per_second))
+                })
+            })
+            .collect();
+
+        handles.into_iter().map(|h| h.join().expect("file worker thread panicked")).collect::<Vec<_>>()
+    });
+
+    let mut progress = FileProgress { finished_count: 0, update_count: 0, bytes_transferred: 0, effective_bytes_per_second: 0 };
+    for result in chunk_results {
+        let (chunk_finished, chunk_updated, chunk_bytes, chunk_rate) = result?;
+        progress.finished_count += chunk_finished;
+        progress.update_count += chunk_updated;
+        progress.bytes_transferred += chunk_bytes;
+        progress.effective_bytes_per_second += chunk_rate;
+    }
+
+    Ok(progress)
+}
+
+/// Log and write the `{work_id}.progress.json` sidecar for this cycle's
+/// aggregated progress, so external dashboards can poll bytes/files
+/// transferred and a computed ETA without parsing logs.
+fn report_progress(
+    context: &GlobusXferContext,
+    work: &TaccSyncWork,
+    progress: &FileProgress,
+    done: bool,
+) -> Result<(), Box<dyn Error>> {
+    let percent_complete = if work.size > 0 {
+        (progress.bytes_transferred as f64 / work.size as f64) * 100.0
+    } else {
+        100.0
+    };
+    let remaining_bytes = work.size.saturating_sub(progress.bytes_transferred);
+    let eta_seconds = if progress.effective_bytes_per_second > 0 {
+        Some(remaining_bytes / progress.effective_bytes_per_second)
+    } else {
+        None
+    };
+
+    info!(
+        "Progress for {}: {:.1}% ({}/{} bytes, {}/{} files transferred){}",
+        work.work_id,
+        percent_complete,
+        progress.bytes_transferred,
+        work.size,
+        progress.finished_count,
+        work.files.len(),
+        match eta_seconds {
+            Some(s) => format!(", ETA {}s", s),
+            None => String::new(),
+        },
+    );
+
+    let report = ProgressReport {
+        work_id: work.work_id,
+        tape: work.tape.clone(),
+        event: if done { "end" } else { "progress" },
+        files_total: work.files.len(),
+        files_transferred: progress.finished_count,
+        total_bytes: work.size,
+        bytes_transferred: progress.bytes_transferred,
+        percent_complete,
+        bytes_per_second: progress.effective_bytes_per_second,
+        eta_seconds,
+    };
+
+    let inbox_dir = PathBuf::from(&context.inbox_dir);
+    tacc_sync::atomic_write_json(&report, &progress_path(&inbox_dir, &work.work_id.to_string()))?;
+    Ok(())
+}
+
 fn process_file(
     context: &GlobusXferContext,
     work_id: &Uuid,
+    active_task_id: Option<&tacc_sync::registry::TaskId>,
     file: &mut TaccSyncFile,
 ) -> Result<TransferUpdate> {
     // log about what we're doing
@@ -213,37 +619,85 @@ fn process_file(
     let mut transfer_update = TransferUpdate {
         finished: false,
         updated: false,
+        bytes_transferred: 0,
+        effective_bytes_per_second: 0,
     };
 
+    // if this file is backing off after a retryable failure and the delay
+    // hasn't elapsed yet, leave it alone this cycle
+    if let Some(retry_after) = file.retry_after {
+        if chrono::Utc::now() < retry_after {
+            info!("File {} is backing off until {}; skipping this cycle.", file.file_name, retry_after);
+            return Ok(transfer_update);
+        }
+    }
+
     // if this file already has a Globus transfer
     if let Some(globus_task_id) = file.globus_task_id {
-        // execute a command to check on the status of the transfer
-        let globus = execute_globus_task_show(globus_task_id)?;
+        // check on the status of the transfer, via whichever backend is configured
+        let globus = execute_globus_task_show(context, globus_task_id)?;
+        transfer_update.bytes_transferred = globus.bytes_transferred;
+        transfer_update.effective_bytes_per_second = globus.effective_bytes_per_second;
 
         // determine what we need to do, given the status of the transfer
         match globus.status.as_str() {
-            // the transfer is in progress; maybe progressing, maybe not
-            "ACTIVE" | "INACTIVE" => {
-                info!("Task ID:{} has status {}.", globus_task_id, globus.status);
+            // the transfer is in progress and making headway
+            "ACTIVE" => {
+                info!("Task ID:{} has status ACTIVE ({} bytes transferred).", globus_task_id, globus.bytes_transferred);
+            },
+            // Globus uses INACTIVE specifically for expired/missing
+            // credentials on one of the endpoints; reactivate and keep polling
+            "INACTIVE" => {
+                info!("Task ID:{} has status INACTIVE; attempting endpoint reactivation.", globus_task_id);
+                if let Err(e) = reactivate_endpoints(context) {
+                    error!("Unable to reactivate endpoints for task {}: {}", globus_task_id, e);
+                }
             },
             // the transfer has finished, we'll tell the caller that
             "SUCCEEDED" => {
                 info!("Task ID:{} has SUCCEEDED.", globus_task_id);
                 transfer_update.finished = true;
             },
-            // the transfer has failed, this requires operator intervention
-            "FAILED" | _ => {
-                error!("Task ID:{} has status {}. Will send to quarantine.", globus_task_id, globus.status);
-                return Err(anyhow::anyhow!("Quarantine due to failed Globus transfer."));
+            // the transfer failed; retry a retryable cause with backoff,
+            // and only give up on quarantine once retries are exhausted
+            "FAILED" => {
+                let reason = globus.nice_status.clone().unwrap_or_else(|| "FAILED".to_string());
+                file.attempt_count += 1;
+                file.last_error = Some(reason.clone());
+
+                if let Some(task_id) = active_task_id {
+                    if let Err(e) = tacc_sync::registry::record_retry(&context.registry_dir, task_id, &file.file_name, file.attempt_count, &reason) {
+                        error!("Unable to record retry in registry: {}", e);
+                    }
+                }
+
+                if is_retryable_failure(globus.nice_status.as_deref()) && file.attempt_count < context.max_retries {
+                    let retry_after = chrono::Utc::now() + backoff_delay(file.attempt_count);
+                    error!(
+                        "Task ID:{} failed ({}); will retry file {} (attempt {}/{}) after {}.",
+                        globus_task_id, reason, file.file_name, file.attempt_count, context.max_retries, retry_after
+                    );
+                    file.retry_after = Some(retry_after);
+                    file.globus_task_id = None;
+                    transfer_update.updated = true;
+                } else {
+                    error!("Task ID:{} has status FAILED ({}); retries exhausted or non-retryable. Will send to quarantine.", globus_task_id, reason);
+                    return Err(anyhow::anyhow!("Quarantine due to failed Globus transfer: {}", reason));
+                }
+            },
+            other => {
+                error!("Task ID:{} has unexpected status {}. Will send to quarantine.", globus_task_id, other);
+                return Err(anyhow::anyhow!("Quarantine due to unexpected Globus task status: {}", other));
             },
         }
-    } 
+    }
     // this file does not yet have a globus transfer
     else {
         // execute the command to create a globus transfer
         let globus = execute_globus_transfer(context, work_id, file)?;
         // take the task_id and update the file
         file.globus_task_id = Some(globus.task_id);
+        file.retry_after = None;
         // indicate that we updated the work unit
         transfer_update.updated = true;
     }
@@ -252,34 +706,83 @@ fn process_file(
     Ok(transfer_update)
 }
 
+/// Whether a FAILED task's `nice_status` indicates a transient cause worth
+/// retrying (an endpoint that isn't activated, a dropped connection) rather
+/// than a permanent one (e.g. a permissions error) that retrying won't fix.
+fn is_retryable_failure(nice_status: Option<&str>) -> bool {
+    matches!(
+        nice_status,
+        Some("ENDPOINT_ERROR") | Some("ENDPOINT_NOT_ACTIVATED") | Some("CONNECTION_FAILED") | Some("EXTERNAL_ERROR") | Some("GC_DISCONNECTED")
+    )
+}
+
+/// Exponential backoff for file retries: doubles with each attempt
+/// starting from `INITIAL_RETRY_DELAY_SECONDS`, capped at `MAX_RETRY_DELAY_SECONDS`.
+fn backoff_delay(attempt_count: u32) -> chrono::Duration {
+    let doublings = attempt_count.saturating_sub(1).min(16);
+    let seconds = INITIAL_RETRY_DELAY_SECONDS.saturating_mul(1u64 << doublings).min(MAX_RETRY_DELAY_SECONDS);
+    chrono::Duration::seconds(seconds as i64)
+}
+
+/// Reactivate both the source and destination endpoints, as Globus
+/// requires after an INACTIVE task signals expired/missing credentials on
+/// one of them.
+fn reactivate_endpoints(context: &GlobusXferContext) -> Result<()> {
+    match &context.backend {
+        GlobusBackend::Cli => {
+            activate_endpoint_cli(&context.globus_source_endpoint)?;
+            activate_endpoint_cli(&context.globus_dest_endpoint)?;
+        },
+        GlobusBackend::Rest(rest) => {
+            rest.autoactivate(&context.globus_source_endpoint)?;
+            rest.autoactivate(&context.globus_dest_endpoint)?;
+        },
+    }
+    Ok(())
+}
+
+fn activate_endpoint_cli(endpoint: &str) -> Result<()> {
+    info!("Running command: globus endpoint activate {}", endpoint);
+    let output = Command::new("globus").arg("endpoint").arg("activate").arg(endpoint).output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(anyhow::anyhow!("globus endpoint activate {} failed: {}", endpoint, stderr));
+    }
+    Ok(())
+}
+
 fn rewrite_work_unit(
-    work: &mut TaccSyncWork, 
+    work: &mut TaccSyncWork,
     inbox_dir: &PathBuf,
 ) -> Result<(), Box<dyn Error>> {
     // log about what we're doing
     let work_unit_path = inbox_dir.join(format!("{}.json", work.work_id));
     info!("Rewriting work unit: {}", work_unit_path.display());
 
-    // rename the old work unit to a safety copy
-    let safety_copy_build = format!("{}.safety", work_unit_path.display());
-    let safety_copy_path = PathBuf::from(safety_copy_build);
-    info!("Making a safety copy at: {}", safety_copy_path.display());
-    fs::rename(&work_unit_path, &safety_copy_path)?;
-
-    // rewrite the work unit for this tape group
-    info!("Creating new work unit at: {}", work_unit_path.display());
-    let file = File::create(work_unit_path)?;
-    serde_json::to_writer_pretty(file, &work)?;
-
-    // remove the safety copy after the successful rewrite
-    info!("Removing the safety copy at: {}", safety_copy_path.display());
-    // fs::remove_file(safety_copy_path)?;
+    // write via the crash-safe temp-file+rename+fsync helper, so a crash
+    // mid-write never leaves the work unit without a readable .json file
+    atomic_write_json(work, &work_unit_path)?;
 
     // tell the caller the work unit was successfully rewritten
     Ok(())
 }
 
-fn execute_globus_task_show(globus_task_id: Uuid) -> Result<GlobusTask> {
+fn execute_globus_task_show(context: &GlobusXferContext, globus_task_id: Uuid) -> Result<GlobusTask> {
+    let globus = match &context.backend {
+        GlobusBackend::Cli => execute_globus_task_show_cli(globus_task_id)?,
+        GlobusBackend::Rest(rest) => rest.task_show(globus_task_id)?,
+    };
+
+    // do some sanity checking here, regardless of which backend answered
+    if globus.task_id != globus_task_id {
+        error!("BAD MOJO -- We asked Globus about {} and we got information back on {} instead!", globus_task_id, globus.task_id);
+        return Err(anyhow::anyhow!("Globus returned task {} when we asked about {}", globus.task_id, globus_task_id));
+    }
+
+    Ok(globus)
+}
+
+fn execute_globus_task_show_cli(globus_task_id: Uuid) -> Result<GlobusTask> {
     // run the command: globus task show {globus_task_id}
     info!("Running command: globus task show {}", globus_task_id);
     let output = Command::new("globus")
@@ -296,14 +799,6 @@ fn execute_globus_task_show(globus_task_id: Uuid) -> Result<GlobusTask> {
 
     // deserialize the GlobusTask and return it to the caller
     let globus: GlobusTask = serde_json::from_str(&stdout)?;
-
-    // do some sanity checking here
-    if globus.task_id != globus_task_id {
-        error!("BAD MOJO -- We asked Globus about {} and we got information back on {} instead!", globus_task_id, globus.task_id);
-        return Err(anyhow::anyhow!("{}", stdout));
-    }
-
-    // tell the caller what we got back from globus
     Ok(globus)
 }
 
@@ -331,9 +826,26 @@ fn execute_globus_transfer(
     let data_warehouse_path = &file.hpss_path[start_index + 1..];
     let dst_file = tacc_dir.join(data_warehouse_path);
 
-    let src_path = format!("{}:{}", src_endpoint, src_file.display());
-    let dst_path = format!("{}:{}", dst_endpoint, dst_file.display());
+    let globus = match &context.backend {
+        GlobusBackend::Cli => {
+            let src_path = format!("{}:{}", src_endpoint, src_file.display());
+            let dst_path = format!("{}:{}", dst_endpoint, dst_file.display());
+            execute_globus_transfer_cli(&src_path, &dst_path)?
+        },
+        GlobusBackend::Rest(rest) => rest.submit_transfer(src_endpoint, &src_file, dst_endpoint, &dst_file)?,
+    };
+
+    // and let's do a sanity check, regardless of which backend answered
+    if globus.code != "Accepted" {
+        error!("BAD MOJO -- Globus responded with {} instead of 'Accepted'", globus.code);
+        return Err(anyhow::anyhow!("BAD MOJO -- Globus responded with {} instead of 'Accepted'", globus.code));
+    }
 
+    // tell the caller about the result of creating the transfer
+    Ok(globus)
+}
+
+fn execute_globus_transfer_cli(src_path: &str, dst_path: &str) -> Result<TransferResult> {
     // run the command: globus transfer {src} {dst}
     info!("Running command: globus transfer {} {}", src_path, dst_path);
     let output = Command::new("globus")
@@ -344,8 +856,8 @@ fn execute_globus_transfer(
         .arg("--verify-checksum")
         .arg("--format")
         .arg("json")
-        .arg(src_path.to_string())
-        .arg(dst_path.to_string())
+        .arg(src_path)
+        .arg(dst_path)
         .output()?;
 
     // capture the output and deserialze the JSON
@@ -354,13 +866,5 @@ fn execute_globus_transfer(
 
     // deserialize the GlobusTask and return it to the caller
     let globus: TransferResult = serde_json::from_str(&stdout)?;
-
-    // and let's do a sanity check
-    if globus.code != "Accepted" {
-        error!("BAD MOJO -- Globus responded with {} instead of 'Accepted'", globus.code);
-        return Err(anyhow::anyhow!("BAD MOJO -- Globus responded with {} instead of 'Accepted'", globus.code));
-    }
-
-    // tell the caller about the result of creating the transfer
     Ok(globus)
 }
@@ -1,78 +1,101 @@
 // reaper.rs
 
-use log::{error, info};
+use chrono::Utc;
 use std::fs;
-use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::Duration;
-use tacc_sync::{
-    boolify, clean_up_and_exit, find_json_files_in_directory,
-    load_work_from_file, move_to_outbox
-};
-
-/// the process exit code indicating successful exit
-const EXIT_SUCCESS: i32 = 0;
+use std::path::{Path, PathBuf};
+use tacc_sync::daemon::{run_daemon, DaemonConfig, WorkOutcome, Worker};
+use tacc_sync::tasklog::TaskLogLayer;
+use tacc_sync::{atomic_write_json, verify_work, TaccSyncWork, WorkPhase};
+use tracing::{error, info};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
 /// the version of the package being compiled
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
+/// Reaper tears down the transfer buffer directory for a finished work unit
+/// once its files have been safely moved downstream.
+struct Reaper {
+    transfer_dir: PathBuf,
+}
+
+impl Worker for Reaper {
+    fn process(&self, work: &mut TaccSyncWork, json_file: &Path) -> WorkOutcome {
+        // a work unit that already made it to Reaping or Done was verified
+        // on a previous, interrupted attempt (and its transfer buffer may
+        // already be partly deleted); no need to verify it again
+        if work.phase != WorkPhase::Reaping && work.phase != WorkPhase::Done {
+            if let Err(e) = verify_work(work, &self.transfer_dir) {
+                error!("Verification failed for {}: {} ({})", work.work_id, e, e.path().display());
+                return WorkOutcome::Quarantine;
+            }
+        }
+
+        reap_work(work, json_file, &self.transfer_dir);
+        WorkOutcome::Advance
+    }
+}
+
 fn main() {
-    // initialize logging
-    env_logger::init();
+    // initialize tracing: events go to stderr as before, and any event
+    // emitted inside a work-unit span is additionally mirrored to that
+    // unit's own log file via TaskLogLayer
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(TaskLogLayer)
+        .init();
     info!("tacc-sync v{} - reaper starting", VERSION);
 
     // load configuration from environment
-    let inbox_dir = std::env::var("INBOX_DIR").expect("INBOX_DIR environment variable not set");
-    let outbox_dir = std::env::var("OUTBOX_DIR").expect("OUTBOX_DIR environment variable not set");
-    let pid_path = std::env::var("PID_PATH").expect("PID_PATH environment variable not set");
-    let quarantine_dir = std::env::var("QUARANTINE_DIR").expect("QUARANTINE_DIR environment variable not set");
-    let run_once_and_die = std::env::var("RUN_ONCE_AND_DIE").expect("RUN_ONCE_AND_DIE environment variable not set");
+    let config = DaemonConfig::from_env().expect("Unable to load daemon configuration");
     let transfer_dir = std::env::var("TRANSFER_DIR").expect("TRANSFER_DIR environment variable not set");
-    let work_sleep_seconds = std::env::var("WORK_SLEEP_SECONDS").expect("WORK_SLEEP_SECONDS environment variable not set");
 
-    let run_once = boolify(&run_once_and_die);
-    let sleep_seconds = work_sleep_seconds.parse::<u64>().expect("WORK_SLEEP_SECONDS environment variable must be an integer");
+    // a SIGTERM/SIGINT just flips this flag; run_daemon finishes and
+    // checkpoints whatever's already in flight before it actually exits
+    let shutdown = tacc_sync::daemon::install_shutdown_flag();
 
-    // run the main loop
-    loop {
-        info!("Starting work cycle");
+    run_daemon(Reaper { transfer_dir: PathBuf::from(transfer_dir) }, config, shutdown);
+}
 
-        // search the inbox for work to do
-        info!("Checking for work in inbox directory: {}", inbox_dir);
-        let json_files = find_json_files_in_directory(&inbox_dir);
-        let num_files = json_files.len();
+/// Reap the transfer buffer for `work`, checkpointing `work.phase` back to
+/// `json_file` before the destructive `remove_dir_all` so a reaper killed
+/// mid-reap can tell, on restart, whether the delete already happened.
+///
+/// # Arguments
+///
+/// * `work` - the work unit to reap; its phase is advanced in place
+/// * `json_file` - the inbox path `work` was loaded from, used to checkpoint
+/// * `transfer_dir` - the root of the transfer buffer
+fn reap_work(work: &mut TaccSyncWork, json_file: &Path, transfer_dir: &PathBuf) {
+    // if a previous reaper already finished this unit, there is nothing left to do
+    if work.phase == WorkPhase::Done {
+        info!("Work {} is already Done; skipping redundant reap", work.work_id);
+        return;
+    }
 
-        // for each unit of work
-        info!("Processing {} work units", num_files);
-        for (index, json_file) in json_files.iter().enumerate() {
-            let json_file_str = json_file.as_path().display();
-            info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
-            // if we are able to load the work from the file
-            if let Ok(work) = load_work_from_file(json_file) {
-                // remove the directory in the transfer buffer
-                info!("Deleting files for {}: {} ({} files - {} bytes)", work.work_id, work.tape, work.files.len(), work.size);
-                let transfer_pb = PathBuf::from(&transfer_dir);
-                let hpss_out_dir = transfer_pb.join(format!("{}", work.work_id));
-                info!("Deleting transfer buffer directory: {}", hpss_out_dir.display());
-                fs::remove_dir_all(&hpss_out_dir).expect("Unable to remove output directory in transfer buffer");
-                // send the work to the finished directory
-                move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
-            }
-            // we weren't able to load the work
-            else {
-                error!("Unable to load TaccSyncWork: {}", json_file_str);
-                move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
-            }
+    info!("Deleting files for {}: {} ({} files - {} bytes)", work.work_id, work.tape, work.files.len(), work.size);
+    let hpss_out_dir = transfer_dir.join(format!("{}", work.work_id));
+
+    // checkpoint the Reaping phase before the destructive step, unless a
+    // previous attempt already got this far
+    if work.phase != WorkPhase::Reaping {
+        work.phase = WorkPhase::Reaping;
+        if let Err(e) = atomic_write_json(work, json_file) {
+            error!("Unable to checkpoint Reaping phase for {}: {}", work.work_id, e);
         }
+    }
 
-        // if this was a one-shot adventure
-        if run_once {
-            info!("RUN_ONCE_AND_DIE: {} -- finisher now ending", run_once_and_die);
-            clean_up_and_exit(&pid_path, EXIT_SUCCESS);
+    info!("Deleting transfer buffer directory: {}", hpss_out_dir.display());
+    if let Err(e) = fs::remove_dir_all(&hpss_out_dir) {
+        // the directory may already be gone from a prior, interrupted attempt
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!("Unable to remove output directory in transfer buffer: {}", e);
         }
+    }
 
-        // otherwise, sleep until we need to wake up again
-        info!("Sleeping for {} seconds...", sleep_seconds);
-        sleep(Duration::from_secs(sleep_seconds));
+    work.phase = WorkPhase::Done;
+    work.reaped_at = Some(Utc::now());
+    if let Err(e) = atomic_write_json(work, json_file) {
+        error!("Unable to checkpoint Done phase for {}: {}", work.work_id, e);
     }
 }
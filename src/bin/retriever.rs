@@ -1,19 +1,31 @@
 // retriever.rs
 
-use log::{debug, error, info};
+use crossbeam_channel::bounded;
+use filetime::FileTime;
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
 use tacc_sync::{
-    boolify, clean_up_and_exit, find_json_files_in_directory,
-    load_work_from_file, move_to_outbox, TaccSyncWork
+    atomic_write_json, boolify, clean_up_and_exit, find_json_files_in_directory,
+    load_work_from_file, move_to_outbox, TaccSyncFile, TaccSyncWork, WorkPhase
 };
 use uuid::Uuid;
 use walkdir::WalkDir;
 
+/// how many bytes to read into the checksum buffer at a time
+const CHECKSUM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// the filename prefix given to every hsi batch scratch file, so a startup
+/// sweep can recognize one left behind by a crashed prior run
+const HSI_BATCH_FILE_PREFIX: &str = "hsi-batch-";
+
 /// the process exit code indicating successful exit
 const EXIT_SUCCESS: i32 = 0;
 
@@ -23,6 +35,7 @@ const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 fn main() {
     // initialize logging
     env_logger::init();
+    let run_started_at = std::time::SystemTime::now();
     info!("tacc-sync v{} - retriever starting", VERSION);
 
     // load configuration from environment
@@ -36,6 +49,44 @@ fn main() {
     let transfer_quota = std::env::var("TRANSFER_QUOTA").expect("TRANSFER_QUOTA environment variable not set");
     let work_sleep_seconds = std::env::var("WORK_SLEEP_SECONDS").expect("WORK_SLEEP_SECONDS environment variable not set");
 
+    // where hsi batch scratch files are written; falls back to
+    // SEMAPHORE_DIR so existing deployments keep working unchanged, but a
+    // dedicated TEMP_DIR keeps transient command input out of the
+    // semaphore/coordination directory
+    let temp_dir = std::env::var("TEMP_DIR").unwrap_or_else(|_| semaphore_dir.clone());
+    fs::create_dir_all(&temp_dir).expect("Unable to create temp directory for hsi batch files");
+
+    // a previous run that was killed mid-batch may have left scratch files
+    // behind; sweep anything matching our naming scheme from before this
+    // process started, so they don't accumulate across restarts
+    sweep_orphaned_batch_files(&PathBuf::from(&temp_dir), run_started_at);
+
+    // how many concurrent hsi `get` streams to run per work unit; defaults
+    // to 1 (strictly sequential, one batch) when RETRIEVER_CONCURRENCY isn't set
+    let retriever_concurrency = match std::env::var("RETRIEVER_CONCURRENCY") {
+        Ok(value) => value.parse::<usize>().expect("RETRIEVER_CONCURRENCY environment variable must be an integer").max(1),
+        Err(_) => 1,
+    };
+
+    // how a full transfer buffer is handled: "stop" (the default) simply
+    // waits for the next cycle; "lru" evicts least-recently-accessed
+    // completed work directories to make room instead
+    let eviction_policy = std::env::var("EVICTION_POLICY").unwrap_or_else(|_| "stop".to_string());
+
+    // a work unit's transfer buffer directory is still needed after
+    // retriever's own inbox/outbox no longer hold its JSON: globus_xfer
+    // keeps transferring out of it, and reaper needs it intact until it
+    // actually verifies and reaps it. EVICTION_PROTECTED_DIRS lets an
+    // operator point the LRU evictor at those downstream stages' inbox/
+    // outbox directories (comma-separated) so it never reclaims a
+    // directory one of them still needs.
+    let eviction_protected_dirs: Vec<String> = std::env::var("EVICTION_PROTECTED_DIRS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect();
+
     let space_allowed = transfer_quota.parse::<u64>().expect("TRANSFER_QUOTA environment variable must be an integer");
     let run_once = boolify(&run_once_and_die);
     let sleep_seconds = work_sleep_seconds.parse::<u64>().expect("WORK_SLEEP_SECONDS environment variable must be an integer");
@@ -55,25 +106,47 @@ fn main() {
             let json_file_str = json_file.as_path().display();
             info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
             // if we are able to load the work from the file
-            if let Ok(work) = load_work_from_file(json_file) {
-                // determine if there is enough space in the transfer buffer
-                let space_required = calculate_directory_size(&PathBuf::from(&transfer_dir)) + work.size;
-                if space_required > space_allowed {
-                    info!("Transfer buffer is full: {} bytes needed > {} bytes allowed", space_required, space_allowed);
-                    info!("Will STOP processing work until the next cycle.");
-                    break;
-                }
-                // process the work
-                if process_work(&work, &PathBuf::from(&transfer_dir), &PathBuf::from(&semaphore_dir)) {
-                    move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
-                } else {
-                    move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
-                }
-            }
-            // we weren't able to load the sync request
-            else {
-                error!("Unable to load TaccSyncWork: {}", json_file_str);
-                move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
+            match load_work_from_file(json_file) {
+                Ok(mut work) => {
+                    // determine if there is enough space in the transfer buffer
+                    let space_required = calculate_directory_size(&PathBuf::from(&transfer_dir)) + work.size;
+                    if space_required > space_allowed {
+                        info!("Transfer buffer is full: {} bytes needed > {} bytes allowed", space_required, space_allowed);
+                        if eviction_policy != "lru" {
+                            info!("Will STOP processing work until the next cycle.");
+                            break;
+                        }
+                        let space_to_free = space_required - space_allowed;
+                        let mut protected_dirs: Vec<&str> = vec![&inbox_dir, &outbox_dir];
+                        protected_dirs.extend(eviction_protected_dirs.iter().map(String::as_str));
+                        let protected_work_ids = collect_protected_work_ids(&protected_dirs);
+                        let freed = evict_lru(&PathBuf::from(&transfer_dir), space_to_free, &protected_work_ids);
+                        if freed < space_to_free {
+                            info!("Eviction only freed {} of {} bytes needed; will STOP processing work until the next cycle.", freed, space_to_free);
+                            break;
+                        }
+                        info!("Evicted {} bytes from the transfer buffer to make room", freed);
+                    }
+                    // checkpoint that retrieval has begun, so a reaper or operator
+                    // inspecting this work unit mid-crash knows it's in flight
+                    if work.phase == WorkPhase::Requested {
+                        work.phase = WorkPhase::Transferring;
+                        if let Err(e) = atomic_write_json(&work, json_file) {
+                            error!("Unable to checkpoint Transferring phase for {}: {}", work.work_id, e);
+                        }
+                    }
+                    // process the work
+                    if process_work(&work, &PathBuf::from(&transfer_dir), &PathBuf::from(&temp_dir), retriever_concurrency) {
+                        let _ = move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
+                    } else {
+                        let _ = move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
+                    }
+                },
+                // we weren't able to load the sync request
+                Err(e) => {
+                    error!("Unable to load TaccSyncWork: {}: {}", json_file_str, e);
+                    let _ = move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
+                },
             }
         }
 
@@ -92,7 +165,8 @@ fn main() {
 fn process_work(
     work: &TaccSyncWork,
     transfer_dir: &PathBuf,
-    semaphore_dir: &PathBuf
+    temp_dir: &PathBuf,
+    concurrency: usize,
 ) -> bool {
     // log about what we're processing
     info!("Retrieving files for {}: {} ({} files - {} bytes)", work.work_id, work.tape, work.files.len(), work.size);
@@ -102,15 +176,88 @@ fn process_work(
     info!("Creating transfer buffer directory: {}", hpss_out_dir.display());
     fs::create_dir_all(&hpss_out_dir).expect("Unable to create output directory in transfer buffer");
 
-    // create a temporary file we can feed to hsi
-    let file_name = Uuid::new_v4().to_string();
-    let hsi_batch_path = semaphore_dir.join(file_name);
+    if !retrieve_concurrently(work, &hpss_out_dir, temp_dir, concurrency) {
+        return false
+    }
+
+    // don't trust hsi's exit status alone: verify every retrieved file's
+    // size and, where we have one, its checksum before declaring success
+    if !verify_retrieved_files(work, &hpss_out_dir) {
+        return false
+    }
+
+    // tell the caller that we succeeded
+    true
+}
+
+/// Split `work.files` into up to `concurrency` batches and retrieve them
+/// concurrently: a producer pushes each batch into a bounded job channel,
+/// `concurrency` worker threads each pull a batch and run their own `hsi -P
+/// in` child for it, and a results channel collects each batch's
+/// success/failure. If a batch fails (the hsi process exits non-zero, or
+/// its output reports a per-file error), the rest of the still-queued
+/// batches are cancelled rather than started.
+fn retrieve_concurrently(work: &TaccSyncWork, hpss_out_dir: &Path, temp_dir: &Path, concurrency: usize) -> bool {
+    if work.files.is_empty() {
+        return true
+    }
+
+    let num_workers = concurrency.max(1).min(work.files.len());
+    let chunk_size = (work.files.len() + num_workers - 1) / num_workers;
+    let batches: Vec<&[TaccSyncFile]> = work.files.chunks(chunk_size).collect();
+    info!("Retrieving {} in {} batch(es) across up to {} worker(s)", work.work_id, batches.len(), num_workers);
+
+    let (job_tx, job_rx) = bounded(batches.len());
+    for batch in &batches {
+        job_tx.send(*batch).expect("job channel unexpectedly closed");
+    }
+    drop(job_tx);
+
+    let (result_tx, result_rx) = bounded(batches.len());
+    let cancelled = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        for worker_id in 0..num_workers {
+            let job_rx = job_rx.clone();
+            let result_tx = result_tx.clone();
+            let cancelled = &cancelled;
+            scope.spawn(move || {
+                while let Ok(batch) = job_rx.recv() {
+                    if cancelled.load(Ordering::Relaxed) {
+                        info!("Worker {} cancelling remaining batch: a prior batch failed", worker_id);
+                        let _ = result_tx.send(false);
+                        continue;
+                    }
+                    let ok = retrieve_batch(work.work_id, batch, hpss_out_dir, temp_dir);
+                    if !ok {
+                        cancelled.store(true, Ordering::Relaxed);
+                    }
+                    let _ = result_tx.send(ok);
+                }
+            });
+        }
+        drop(result_tx);
+    });
+
+    result_rx.iter().fold(true, |all_ok, ok| all_ok && ok)
+}
+
+/// Write one batch of files to its own hsi batch file, run `hsi -P in` on
+/// it, and check both hsi's exit status and its per-transfer output for
+/// this batch's files.
+fn retrieve_batch(work_id: Uuid, batch: &[TaccSyncFile], hpss_out_dir: &Path, temp_dir: &Path) -> bool {
+    // create a temporary file we can feed to hsi; unique per batch so
+    // concurrent workers never collide on the same file, and named with a
+    // recognizable prefix so a later run can tell a leftover batch file
+    // apart from anything else that might end up in this directory
+    let file_name = format!("{}{}", HSI_BATCH_FILE_PREFIX, Uuid::new_v4());
+    let hsi_batch_path = temp_dir.join(file_name);
     let hsi_batch_file = File::create(&hsi_batch_path).expect("Unable to create hsi batch temporary file");
     info!("hsi batch file: {}", hsi_batch_path.display());
     let mut writer = BufWriter::new(hsi_batch_file);
 
     // we batch the hsi copy commands into the file
-    for file in &work.files {
+    for file in batch {
         let output_path = hpss_out_dir.join(&file.file_name);
         let hpss_path = &file.hpss_path;
         writeln!(writer, "get -C -P {} : {}", output_path.display(), hpss_path).expect("Unable to write to hsi batch temporary file");
@@ -119,41 +266,329 @@ fn process_work(
 
     // run the hsi command, feeding it the batch file
     info!("Running hsi command: hsi -P in {}", hsi_batch_path.display());
+    let run_result = run_hsi_batch(&hsi_batch_path);
+
+    // remove our temporary file
+    info!("Removing hsi batch file: {}", hsi_batch_path.display());
+    std::fs::remove_file(hsi_batch_path).expect("Unable to delete hsi batch temporary file");
+
+    let (stdout, stderr) = match run_result {
+        Ok(output) => output,
+        Err(e) => {
+            error!("hsi batch retrieval for {}: {}", work_id, e);
+            return false
+        },
+    };
+    debug!("{}", stdout);
+    if !stderr.is_empty() {
+        debug!("{}", stderr);
+    }
+
+    // the process exited 0, but hsi reports per-transfer failures (missing
+    // files, HPSS errors) inline in its output rather than via exit status;
+    // scan for those before trusting anything else it told us
+    let combined_output = format!("{}\n{}", stdout, stderr);
+    let mut any_failed = false;
+    for (output_path, result) in per_file_hsi_report(batch, hpss_out_dir, &combined_output) {
+        if let Err(reason) = result {
+            error!("hsi reported a failure retrieving {}: {}", output_path.display(), reason);
+            any_failed = true;
+        }
+    }
+
+    !any_failed
+}
+
+/// HsiCommandError is a rich error for a failed `hsi` invocation: the
+/// command and args that were run, its exit status, and its full captured
+/// stdout/stderr, so a caller can log (or quarantine) enough detail to
+/// diagnose the failure instead of just "hsi failed".
+#[derive(Debug)]
+struct HsiCommandError {
+    command: String,
+    args: Vec<String>,
+    status: std::process::ExitStatus,
+    stdout: String,
+    stderr: String,
+}
+
+impl std::fmt::Display for HsiCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{} {}` exited with {}; stdout={:?} stderr={:?}",
+            self.command, self.args.join(" "), self.status, self.stdout, self.stderr
+        )
+    }
+}
+
+impl std::error::Error for HsiCommandError {}
+
+/// Run `hsi -P in <hsi_batch_path>`, capturing stdout and stderr. Returns
+/// an `HsiCommandError` (rather than swallowing the failure) if the process
+/// exits non-zero.
+fn run_hsi_batch(hsi_batch_path: &Path) -> Result<(String, String), HsiCommandError> {
+    let args = vec!["-P".to_string(), "in".to_string(), hsi_batch_path.display().to_string()];
     let output = Command::new("hsi")
         .arg("-P")
         .arg("in")
-        .arg(&hsi_batch_path)
+        .arg(hsi_batch_path)
         .output()
         .expect("Unable to execute hsi batch copy for work unit");
 
-    // remove our temporary file
-    info!("Removing hsi batch file: {}", hsi_batch_path.display());
-    std::fs::remove_file(hsi_batch_path).expect("Unable to delete hsi batch temporary file");
-
-    // check the output to see that everything succeeded?
     let stdout = String::from_utf8(output.stdout).expect("hsi output does not conform to utf8 encoding");
-    debug!("{}", stdout);
+    let stderr = String::from_utf8(output.stderr).expect("hsi output does not conform to utf8 encoding");
 
-    // tell the caller that we succeeded
-    return true
+    if !output.status.success() {
+        return Err(HsiCommandError {
+            command: "hsi".to_string(),
+            args,
+            status: output.status,
+            stdout,
+            stderr,
+        });
+    }
+
+    Ok((stdout, stderr))
+}
+
+/// lines in hsi output matching any of these substrings indicate a
+/// per-transfer failure rather than routine progress chatter
+const HSI_ERROR_MARKERS: &[&str] = &["HPSS_E", "*** HSI", "No such file"];
+
+/// Scan `combined_output` for hsi's per-transfer error markers and build a
+/// per-file report: `Err(line)` if a line naming that file's `hpss_path`
+/// also matched an error marker, `Ok(())` otherwise.
+fn per_file_hsi_report(files: &[TaccSyncFile], hpss_out_dir: &Path, combined_output: &str) -> Vec<(PathBuf, Result<(), String>)> {
+    files.iter().map(|file| {
+        let output_path = hpss_out_dir.join(&file.file_name);
+        let failure = combined_output.lines().find(|line| {
+            line.contains(&file.hpss_path) && HSI_ERROR_MARKERS.iter().any(|marker| line.contains(marker))
+        });
+        match failure {
+            Some(line) => (output_path, Err(line.to_string())),
+            None => (output_path, Ok(())),
+        }
+    }).collect()
+}
+
+/// Verify every file `work` was supposed to retrieve actually landed in
+/// `hpss_out_dir` with the right size and, if `checksum` is known, the
+/// right content. Returns `false` (after logging which files mismatched)
+/// if any file fails either check.
+fn verify_retrieved_files(work: &TaccSyncWork, hpss_out_dir: &Path) -> bool {
+    let mut all_verified = true;
+    for file in &work.files {
+        let output_path = hpss_out_dir.join(&file.file_name);
+        if let Err(reason) = verify_one_file(&output_path, file) {
+            error!("Verification failed for {}: {}", output_path.display(), reason);
+            all_verified = false;
+        }
+    }
+    all_verified
+}
+
+/// Verify one retrieved file's on-disk size matches `file.size`, and, if
+/// `file.checksum` is known, stream it through BLAKE3 and compare the hex
+/// digest against the expected value.
+fn verify_one_file(output_path: &Path, file: &TaccSyncFile) -> Result<(), String> {
+    let metadata = fs::metadata(output_path).map_err(|e| format!("unable to stat retrieved file: {}", e))?;
+    if metadata.len() != file.size {
+        return Err(format!("size mismatch: expected {} bytes, found {}", file.size, metadata.len()));
+    }
+
+    let Some(expected_checksum) = &file.checksum else {
+        warn!("No checksum recorded for {}; only the size was verified", output_path.display());
+        return Ok(());
+    };
+
+    let mut reader = File::open(output_path).map_err(|e| format!("unable to open retrieved file: {}", e))?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = [0u8; CHECKSUM_CHUNK_SIZE];
+    loop {
+        let n = reader.read(&mut buf).map_err(|e| format!("unable to read retrieved file: {}", e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let digest = hasher.finalize().to_hex().to_string();
+    if &digest != expected_checksum {
+        return Err(format!("checksum mismatch: expected {}, computed {}", expected_checksum, digest));
+    }
+
+    Ok(())
+}
+
+/// Remove any leftover hsi batch scratch files from `temp_dir`: files whose
+/// name starts with `HSI_BATCH_FILE_PREFIX` and whose modified time predates
+/// `run_started_at`. A batch file is only ever written and removed within a
+/// single `retrieve_batch` call, so anything matching that's still around
+/// from before this run started must have been orphaned by a crash.
+fn sweep_orphaned_batch_files(temp_dir: &Path, run_started_at: std::time::SystemTime) {
+    let entries = match fs::read_dir(temp_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to scan temp directory {} for orphaned batch files: {}", temp_dir.display(), e);
+            return
+        },
+    };
+
+    let mut swept = 0usize;
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let is_batch_file = path.file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(HSI_BATCH_FILE_PREFIX))
+            .unwrap_or(false);
+        if !is_batch_file {
+            continue;
+        }
+        let is_stale = fs::metadata(&path)
+            .and_then(|m| m.modified())
+            .map(|modified| modified < run_started_at)
+            .unwrap_or(false);
+        if !is_stale {
+            continue;
+        }
+        info!("Sweeping orphaned hsi batch file from a previous run: {}", path.display());
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Unable to remove orphaned hsi batch file {}: {}", path.display(), e);
+            continue;
+        }
+        swept += 1;
+    }
+
+    if swept > 0 {
+        info!("Swept {} orphaned hsi batch file(s) from {}", swept, temp_dir.display());
+    }
+}
+
+/// Collect the `work_id`s of every `TaccSyncWork` JSON currently sitting in
+/// `dirs`, i.e. work this binary's own inbox/outbox still considers
+/// unprocessed, plus whatever downstream stages' directories the caller
+/// passes in via `EVICTION_PROTECTED_DIRS`. Their transfer buffer
+/// directories must never be evicted out from under them.
+fn collect_protected_work_ids(dirs: &[&str]) -> HashSet<Uuid> {
+    let mut protected = HashSet::new();
+    for dir in dirs {
+        for json_file in find_json_files_in_directory(dir) {
+            if let Ok(work) = load_work_from_file(&json_file) {
+                protected.insert(work.work_id);
+            }
+        }
+    }
+    protected
+}
+
+/// Evict whole `work_id` subdirectories of `transfer_dir`, oldest-accessed
+/// first, until at least `space_to_free` bytes have been reclaimed or every
+/// evictable (unprotected) directory has been removed. Returns the number
+/// of bytes actually freed.
+fn evict_lru(transfer_dir: &Path, space_to_free: u64, protected_work_ids: &HashSet<Uuid>) -> u64 {
+    let entries = match fs::read_dir(transfer_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to scan transfer buffer {} for eviction: {}", transfer_dir.display(), e);
+            return 0
+        },
+    };
+
+    let mut candidates: Vec<(PathBuf, FileTime, u64)> = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_dir() {
+            continue;
+        }
+        let work_id = match path.file_name().and_then(|n| n.to_str()).and_then(|n| Uuid::parse_str(n).ok()) {
+            Some(id) => id,
+            None => continue, // not one of our work_id subdirectories; leave it alone
+        };
+        if protected_work_ids.contains(&work_id) {
+            continue;
+        }
+        candidates.push((path.clone(), directory_last_access_time(&path), calculate_directory_size(&path)));
+    }
+
+    // least-recently-accessed first
+    candidates.sort_by_key(|(_, last_accessed, _)| *last_accessed);
+
+    let mut freed = 0u64;
+    for (path, _, size) in candidates {
+        if freed >= space_to_free {
+            break;
+        }
+        info!("Evicting transfer buffer directory {} ({} bytes, least recently accessed)", path.display(), size);
+        if let Err(e) = fs::remove_dir_all(&path) {
+            error!("Unable to evict transfer buffer directory {}: {}", path.display(), e);
+            continue;
+        }
+        freed += size;
+    }
+
+    freed
+}
+
+/// The most recent access time of any file anywhere under `dir_path`, used
+/// as that work unit's "last touched" time for LRU ordering. An empty or
+/// unreadable directory sorts as the Unix epoch, so it's evicted first.
+fn directory_last_access_time(dir_path: &Path) -> FileTime {
+    WalkDir::new(dir_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter_map(|e| fs::metadata(e.path()).ok())
+        .map(|metadata| FileTime::from_last_access_time(&metadata))
+        .max()
+        .unwrap_or_else(FileTime::zero)
 }
 
 /// Calculate the total size of files in a directory and its subdirectories.
-/// 
+///
+/// The directory is listed up front on one thread (that part is cheap),
+/// then the per-file `stat` calls -- which dominate on a transfer buffer
+/// holding many recalled files -- are fanned out across a pool of worker
+/// threads, sized by `SIZE_SCAN_THREADS` if set or the machine's available
+/// parallelism otherwise.
+///
 /// # Arguments
-/// 
+///
 /// * `root_path` - The path to the directory whose total size should be calculated.
-/// 
+///
 /// # Returns
-/// 
+///
 /// The total size of all files in bytes as a `u64`.
 fn calculate_directory_size(root_path: &PathBuf) -> u64 {
-    WalkDir::new(root_path)
+    let paths: Vec<PathBuf> = WalkDir::new(root_path)
         .into_iter()
         .filter_map(|e| e.ok()) // Filter out any Errs and unwrap
         .filter(|e| e.file_type().is_file()) // Consider only files
         .map(|e| e.path().to_owned()) // Convert DirEntry to Path
-        .filter_map(|path| fs::metadata(path).ok()) // Get metadata, filter out errors
-        .filter_map(|metadata| metadata.len().checked_add(0)) // Extract file size, ignore files we can't get size for
-        .sum() // Sum up all file sizes
+        .collect();
+
+    if paths.is_empty() {
+        return 0;
+    }
+
+    let num_threads = match std::env::var("SIZE_SCAN_THREADS") {
+        Ok(value) => value.parse::<usize>().expect("SIZE_SCAN_THREADS environment variable must be an integer").max(1),
+        Err(_) => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    }.min(paths.len());
+    let chunk_size = (paths.len() + num_threads - 1) / num_threads;
+
+    let total = AtomicU64::new(0);
+    thread::scope(|scope| {
+        for chunk in paths.chunks(chunk_size) {
+            let total = &total;
+            scope.spawn(move || {
+                let chunk_total: u64 = chunk.iter()
+                    .filter_map(|path| fs::metadata(path).ok()) // Get metadata, filter out errors
+                    .map(|metadata| metadata.len()) // Extract file size
+                    .sum();
+                total.fetch_add(chunk_total, Ordering::Relaxed);
+            });
+        }
+    });
+
+    total.load(Ordering::Relaxed)
 }
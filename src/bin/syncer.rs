@@ -2,16 +2,23 @@
 
 use chrono::Utc;
 use globset::{Glob, GlobSetBuilder};
-use log::{error, info};
+use serde::Serialize;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::thread::sleep;
 use std::time::Duration;
+use tacc_sync::daemon::TokenPool;
+use tacc_sync::tasklog::TaskLogGuard;
 use tacc_sync::{
-    boolify, clean_up_and_exit, find_json_files_in_directory, load_request_from_file, move_to_outbox, HpssFile, TaccSyncFile, TaccSyncRequest, TaccSyncWork
+    boolify, clean_up_and_exit, find_json_files_in_directory, load_request_from_file, move_to_outbox, HpssFile, TaccSyncFile, TaccSyncRequest, TaccSyncWork, WorkPhase
 };
+use tracing::{error, info, info_span, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use uuid::Uuid;
 
 /// the process exit code indicating successful exit
@@ -24,21 +31,51 @@ const NUM_HSI_METADATA_FIELDS: usize = 13;
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 fn main() {
-    // initialize logging
-    env_logger::init();
+    // initialize tracing: events go to stderr as before, and any event
+    // emitted inside a request's span is additionally mirrored to that
+    // request's own log file via TaskLogLayer, so the request's full
+    // lifecycle can be reconstructed from one file
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer())
+        .with(tacc_sync::tasklog::TaskLogLayer)
+        .init();
     info!("tacc-sync v{} - syncer starting", VERSION);
 
     // load configuration from environment
     let hsi_base_path = std::env::var("HSI_BASE_PATH").expect("HSI_BASE_PATH environment variable not set");
     let inbox_dir = std::env::var("INBOX_DIR").expect("INBOX_DIR environment variable not set");
+    let lease_dir = std::env::var("LEASE_DIR").expect("LEASE_DIR environment variable not set");
     let outbox_dir = std::env::var("OUTBOX_DIR").expect("OUTBOX_DIR environment variable not set");
     let pid_path = std::env::var("PID_PATH").expect("PID_PATH environment variable not set");
     let quarantine_dir = std::env::var("QUARANTINE_DIR").expect("QUARANTINE_DIR environment variable not set");
     let run_once_and_die = std::env::var("RUN_ONCE_AND_DIE").expect("RUN_ONCE_AND_DIE environment variable not set");
     let semaphore_dir = std::env::var("SEMAPHORE_DIR").expect("SEMAPHORE_DIR environment variable not set");
+    let status_dir = std::env::var("STATUS_DIR").expect("STATUS_DIR environment variable not set");
+    let task_log_dir = std::env::var("TASK_LOG_DIR").expect("TASK_LOG_DIR environment variable not set");
     let work_dir = std::env::var("WORK_DIR").expect("WORK_DIR environment variable not set");
     let work_sleep_seconds = std::env::var("WORK_SLEEP_SECONDS").expect("WORK_SLEEP_SECONDS environment variable not set");
 
+    // the size of the worker pool used both to dispatch inbox requests
+    // concurrently and to fan out each request's hsi tape-metadata batch;
+    // defaults to 1 (strictly sequential) when WORKER_THREADS isn't set
+    let worker_threads = match std::env::var("WORKER_THREADS") {
+        Ok(value) => value.parse::<usize>().expect("WORKER_THREADS environment variable must be an integer").max(1),
+        Err(_) => 1,
+    };
+
+    // caps on how big (in bytes and in file count) a single TaccSyncWork
+    // unit is allowed to grow; a tape group that exceeds either cap is split
+    // into multiple sequential work units instead of one monolithic one.
+    // unset means uncapped, i.e. one work unit per tape as before
+    let max_work_unit_bytes = match std::env::var("MAX_WORK_UNIT_BYTES") {
+        Ok(value) => value.parse::<u64>().expect("MAX_WORK_UNIT_BYTES environment variable must be an integer"),
+        Err(_) => u64::MAX,
+    };
+    let max_work_unit_files = match std::env::var("MAX_WORK_UNIT_FILES") {
+        Ok(value) => value.parse::<usize>().expect("MAX_WORK_UNIT_FILES environment variable must be an integer").max(1),
+        Err(_) => usize::MAX,
+    };
+
     let run_once = boolify(&run_once_and_die);
     let sleep_seconds = work_sleep_seconds.parse::<u64>().expect("WORK_SLEEP_SECONDS environment variable must be an integer");
 
@@ -51,24 +88,61 @@ fn main() {
         let json_files = find_json_files_in_directory(&inbox_dir);
         let num_files = json_files.len();
 
-        // for each unit of work
-        info!("Processing {} work units", num_files);
-        for (index, json_file) in json_files.iter().enumerate() {
-            let json_file_str = json_file.as_path().display();
-            info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
-            // if we are able to load the sync request from the file
-            if let Ok(request) = load_request_from_file(json_file) {
-                // process the sync request
-                process_sync_request(&request, &hsi_base_path, &PathBuf::from(&semaphore_dir), &PathBuf::from(&work_dir));
-                // move the request to the outbox
-                move_to_outbox(json_file, &PathBuf::from(&outbox_dir));
-            }
-            // we weren't able to load the sync request
-            else {
-                error!("Unable to load TaccSyncRequest: {}", json_file_str);
-                move_to_outbox(json_file, &PathBuf::from(&quarantine_dir));
+        // dispatch each request onto up to worker_threads worker threads; a
+        // failing request only quarantines itself, it never blocks the rest
+        info!("Processing {} work units with up to {} in flight", num_files, worker_threads);
+        let tokens = TokenPool::new(worker_threads);
+        let completed = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for (index, json_file) in json_files.iter().enumerate() {
+                tokens.acquire();
+                let release = tokens.returns();
+                let completed = &completed;
+                let hsi_base_path = &hsi_base_path;
+                let lease_dir = &lease_dir;
+                let semaphore_dir = &semaphore_dir;
+                let status_dir = &status_dir;
+                let task_log_dir = &task_log_dir;
+                let work_dir = &work_dir;
+                let outbox_dir = &outbox_dir;
+                let quarantine_dir = &quarantine_dir;
+
+                scope.spawn(move || {
+                    let json_file_str = json_file.as_path().display();
+                    info!("Processing {}/{}: {}", index+1, num_files, json_file_str);
+                    // if we are able to load the sync request from the file
+                    match load_request_from_file(json_file) {
+                        Ok(request) => {
+                            let request_id = request.request_id.to_string();
+                            let span = info_span!("sync_request", request_id = %request.request_id);
+                            let span_guard = span.enter();
+                            let log_guard = TaskLogGuard::open(&PathBuf::from(task_log_dir), &request_id)
+                                .map_err(|e| error!("Unable to open per-request log file for {}: {}", request_id, e))
+                                .ok();
+
+                            // process the sync request
+                            process_sync_request(&request, hsi_base_path, &PathBuf::from(semaphore_dir), &PathBuf::from(work_dir), &PathBuf::from(status_dir), &PathBuf::from(lease_dir), worker_threads, max_work_unit_bytes, max_work_unit_files);
+
+                            // drop the guards so the log file is flushed and closed before we try to move it
+                            drop(log_guard);
+                            drop(span_guard);
+
+                            // move the request (and its log file) to the outbox
+                            let _ = move_to_outbox(json_file, &PathBuf::from(outbox_dir));
+                            TaskLogGuard::move_to(&PathBuf::from(task_log_dir), &request_id, &PathBuf::from(outbox_dir));
+                        },
+                        // we weren't able to load the sync request
+                        Err(e) => {
+                            error!("Unable to load TaccSyncRequest: {}: {}", json_file_str, e);
+                            let _ = move_to_outbox(json_file, &PathBuf::from(quarantine_dir));
+                        },
+                    }
+                    let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                    info!("Finished {}/{}: {}", done, num_files, json_file_str);
+                    let _ = release.send(());
+                });
             }
-        }
+        });
 
         // if this was a one-shot adventure
         if run_once {
@@ -82,19 +156,55 @@ fn main() {
     }
 }
 
-fn process_sync_request(request: &TaccSyncRequest, hsi_base_path: &str, semaphore_dir: &PathBuf, work_dir: &PathBuf) {
+fn process_sync_request(request: &TaccSyncRequest, hsi_base_path: &str, semaphore_dir: &PathBuf, work_dir: &PathBuf, status_dir: &PathBuf, lease_dir: &PathBuf, worker_threads: usize, max_work_unit_bytes: u64, max_work_unit_files: usize) {
+    let request_id = request.request_id;
+
     // query hsi for all icecube files
-    let paths = query_hsi_all_files(hsi_base_path);
+    report_phase(status_dir, request_id, tacc_sync::status::RequestPhase::QueryingHsi);
+    let span = info_span!("query_hsi_all_files", request_id = %request_id, phase = "querying_hsi");
+    let paths = span.in_scope(|| query_hsi_all_files(hsi_base_path));
+
     // filter out the icecube files that match the pattern
-    let request_files = filter_request_files(paths, &request.pattern);
+    report_phase(status_dir, request_id, tacc_sync::status::RequestPhase::Filtering);
+    let span = info_span!("filter_request_files", request_id = %request_id, phase = "filtering");
+    let request_files = span.in_scope(|| filter_request_files(paths, &request.pattern));
+
     // query hsi for file metadata including tape location
-    let file_metadata = query_hsi_tape_metadata(request_files, semaphore_dir);
-    // sort hsi metadata by tape and position
-    let mut hpss_files = parse_tape_metadata(file_metadata);
-    // group the metadata into per-tape groups
-    let tape_groups = group_files_by_tape(&mut hpss_files);
-    // generate per-tape work units
-    generate_work_units(request, &tape_groups, work_dir);
+    report_phase(status_dir, request_id, tacc_sync::status::RequestPhase::QueryingMetadata);
+    let span = info_span!("query_hsi_tape_metadata", request_id = %request_id, phase = "querying_metadata");
+    let file_metadata = span.in_scope(|| query_hsi_tape_metadata(request_files, semaphore_dir, worker_threads));
+
+    // sort hsi metadata by tape and position, then group into per-tape groups
+    report_phase(status_dir, request_id, tacc_sync::status::RequestPhase::Grouping);
+    let span = info_span!("parse_tape_metadata", request_id = %request_id, phase = "grouping");
+    let (mut hpss_files, rejected) = span.in_scope(|| parse_tape_metadata(file_metadata));
+    if !rejected.is_empty() {
+        write_rejected_metadata_report(work_dir, request_id, &rejected);
+    }
+    let matched_files = hpss_files.len();
+    let total_bytes: u64 = hpss_files.iter().map(|f| f.size).sum();
+    if let Err(e) = tacc_sync::status::set_matched_files(status_dir, request_id, matched_files, total_bytes) {
+        error!("Unable to record matched-file status for request {}: {}", request_id, e);
+    }
+    let span = info_span!("group_files_by_tape", request_id = %request_id, phase = "grouping");
+    let tape_groups = span.in_scope(|| group_files_by_tape(&mut hpss_files));
+    if let Err(e) = tacc_sync::status::set_tape_groups(status_dir, request_id, tape_groups.len()) {
+        error!("Unable to record tape-group status for request {}: {}", request_id, e);
+    }
+
+    // generate per-tape work units, resuming from the lease if a previous
+    // cycle already committed some of them before being killed
+    report_phase(status_dir, request_id, tacc_sync::status::RequestPhase::GeneratingUnits);
+    let span = info_span!("generate_work_units", request_id = %request_id, phase = "generating_units");
+    span.in_scope(|| generate_work_units(request, &tape_groups, work_dir, lease_dir, max_work_unit_bytes, max_work_unit_files));
+}
+
+/// Move `request_id`'s status record to `phase`, logging rather than
+/// failing the whole request if the status write itself fails.
+fn report_phase(status_dir: &Path, request_id: Uuid, phase: tacc_sync::status::RequestPhase) {
+    if let Err(e) = tacc_sync::status::set_phase(status_dir, request_id, phase) {
+        error!("Unable to record status for request {}: {}", request_id, e);
+    }
 }
 
 fn query_hsi_all_files(hsi_base_path: &str) -> Vec<String> {
@@ -113,7 +223,9 @@ fn query_hsi_all_files(hsi_base_path: &str) -> Vec<String> {
     let stderr = String::from_utf8(output.stderr).expect("hsi output does not conform to utf8 encoding");
 
     // return the vector containing one directory or file per line
-    stderr.lines().map(ToString::to_string).collect::<Vec<String>>()
+    let paths = stderr.lines().map(ToString::to_string).collect::<Vec<String>>();
+    info!(path_count = paths.len(), "hsi listing complete");
+    paths
 }
 
 fn filter_request_files(paths: Vec<String>, pattern: &str) -> Vec<String> {
@@ -123,17 +235,53 @@ fn filter_request_files(paths: Vec<String>, pattern: &str) -> Vec<String> {
     let glob_set = GlobSetBuilder::new().add(glob).build().expect("failed to build glob set");
 
     // filter the paths based on the glob set
-    paths.into_iter()
+    let request_files = paths.into_iter()
         .filter(|path| {
             glob_set.is_match(path)
         })
-        .collect::<Vec<String>>()
+        .collect::<Vec<String>>();
+    info!(matched_files = request_files.len(), "filtering complete");
+    request_files
 }
 
-fn query_hsi_tape_metadata(request_files: Vec<String>, semaphore_dir: &PathBuf) -> Vec<String> {
+fn query_hsi_tape_metadata(request_files: Vec<String>, semaphore_dir: &PathBuf, worker_threads: usize) -> Vec<String> {
     // log about what we're doing
-    info!("Querying hsi for tape metadata for {} files", request_files.len());
+    info!(file_count = request_files.len(), "Querying hsi for tape metadata");
+
+    if request_files.is_empty() {
+        return Vec::new();
+    }
+
+    // split the files across up to worker_threads batches, each with its own
+    // UUID-named batch file so concurrent hsi invocations never collide; this
+    // caps this request's concurrent hsi child processes at worker_threads.
+    // The cap is per request: main runs up to worker_threads requests at
+    // once, each with its own batches, so up to worker_threads squared hsi
+    // children can run across the process
+    let num_workers = worker_threads.max(1).min(request_files.len());
+    let chunk_size = (request_files.len() + num_workers - 1) / num_workers;
+    info!(worker_count = num_workers, "Querying hsi for tape metadata across multiple workers");
+
+    let mut metadata = Vec::new();
+    thread::scope(|scope| {
+        let handles: Vec<_> = request_files
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| query_hsi_tape_metadata_batch(chunk, semaphore_dir)))
+            .collect();
+
+        for handle in handles {
+            metadata.extend(handle.join().expect("hsi tape metadata worker thread panicked"));
+        }
+    });
 
+    info!(metadata_line_count = metadata.len(), "hsi tape metadata query complete");
+    metadata
+}
+
+/// Run one batch of `ls -NP` hsi tape-metadata queries, via its own
+/// UUID-named batch file in `semaphore_dir` so it can run concurrently with
+/// other batches without colliding on the same temporary file.
+fn query_hsi_tape_metadata_batch(request_files: &[String], semaphore_dir: &PathBuf) -> Vec<String> {
     // create a temporary file we can feed to hsi
     let file_name = Uuid::new_v4().to_string();
     let hsi_batch_file = semaphore_dir.join(file_name);
@@ -166,75 +314,122 @@ fn query_hsi_tape_metadata(request_files: Vec<String>, semaphore_dir: &PathBuf)
     stdout.lines().map(ToString::to_string).collect::<Vec<String>>()
 }
 
-fn parse_tape_metadata(file_metadata: Vec<String>) -> Vec<HpssFile> {
-    // parse the metadata lines
-    info!("Parsing metadata from {} hsi files into HpssFile objects", file_metadata.len());
-    let mut hpss_files = Vec::new();
+/// RejectedMetadataLine records one hsi metadata line that couldn't be
+/// parsed into an `HpssFile`, and why, so it can be inspected or re-driven
+/// later instead of silently vanishing.
+#[derive(Debug, Serialize)]
+struct RejectedMetadataLine {
+    line: String,
+    reason: String,
+}
 
-    // hpss output will come back like this:
-    // ls -NP /home/projects/icecube/data/exp/IceCube/2009/unbiased/PFRaw/0101/cd88bb827ab811eba0ccfac645b4ea48.zip
-    // FILE    /home/projects/icecube/data/exp/IceCube/2009/unbiased/PFRaw/0101/cd88bb827ab811eba0ccfac645b4ea48.zip   99658060045     99658060045     840+0   AG084600        5       0       1       03/01/2021      11:15:47        03/01/2021
-    //         11:30:52
-
-    // we care about the second line (the response to the command) ...
-    //  0 // FILE
-    //  1 // /home/projects/icecube/data/exp/IceCube/2011/unbiased/PFRaw/1109/b26eac34-7848-49de-a7c2-193e954af803.zip
-    //  2 // 568860644320
-    //  3 // 568860644320
-    //  4 // 119+558936243566
-    //  5 // AU031800,AU031900
-    //  6 // 12
-    //  7 // 0
-    //  8 // 1
-    //  9 // 04/07/2017
-    // 10 // 02:19:14
-    // 11 // 04/07/2017
-    // 12 // 03:07:47
-    // 13 ........................ length
-    for metadata in file_metadata {
-        let fields = metadata.split('\t').map(|s| s.to_string()).collect::<Vec<String>>();
+/// Parse one hsi `ls -NP` response line into an `HpssFile`.
+///
+/// `Ok(None)` means the line wasn't a `FILE` response at all (e.g. it was
+/// the echoed command) and should simply be ignored. `Err` carries a reason
+/// a malformed line couldn't be parsed, for the caller to collect rather
+/// than abort on.
+///
+/// hpss output will come back like this:
+/// ls -NP /home/projects/icecube/data/exp/IceCube/2009/unbiased/PFRaw/0101/cd88bb827ab811eba0ccfac645b4ea48.zip
+/// FILE    /home/projects/icecube/data/exp/IceCube/2009/unbiased/PFRaw/0101/cd88bb827ab811eba0ccfac645b4ea48.zip   99658060045     99658060045     840+0   AG084600        5       0       1       03/01/2021      11:15:47        03/01/2021
+///         11:30:52
+///
+/// we care about the second line (the response to the command) ...
+///  0 // FILE
+///  1 // /home/projects/icecube/data/exp/IceCube/2011/unbiased/PFRaw/1109/b26eac34-7848-49de-a7c2-193e954af803.zip
+///  2 // 568860644320
+///  3 // 568860644320
+///  4 // 119+558936243566
+///  5 // AU031800,AU031900
+///  6 // 12
+///  7 // 0
+///  8 // 1
+///  9 // 04/07/2017
+/// 10 // 02:19:14
+/// 11 // 04/07/2017
+/// 12 // 03:07:47
+/// 13 ........................ length
+fn parse_metadata_line(metadata: &str) -> Result<Option<HpssFile>, String> {
+    let fields = metadata.split('\t').collect::<Vec<&str>>();
+
+    // if fields[0] is not 'FILE', it's probably the echoed command; ignore it
+    if fields.is_empty() || fields[0] != "FILE" {
+        return Ok(None);
+    }
 
-        // if fields[0] is not 'FILE', it's probably the command; ignore it
-        if fields[0] != "FILE" {
-            continue;
-        }
+    // if we didn't get the proper number of fields, quarantine the line
+    if fields.len() != NUM_HSI_METADATA_FIELDS {
+        return Err(format!("expected {} tab-separated fields, got {}", NUM_HSI_METADATA_FIELDS, fields.len()));
+    }
 
-        // if we didn't get the proper number of fields, it is BAD MOJO
-        if fields.len() != NUM_HSI_METADATA_FIELDS {
-            // log about it and die; we leave no file behind!
-            error!("hsi metadata parse error: NUM_HSI_METADATA_FIELDS={}, fields.len()={}", NUM_HSI_METADATA_FIELDS, fields.len());
-            error!("Line: {}", metadata);
-            panic!("BAD MOJO - hsi metadata parse error: NUM_HSI_METADATA_FIELDS");
+    // if the tape is specified, use it, otherwise call it "0"
+    let tape = if fields[5].len() < 3 { "0".to_string() } else { fields[5].to_string() };
+
+    // if fields[4] has a + we've got tape number and offset, otherwise call them "0"
+    let (tape_num, tape_offset) = if fields[4].contains('+') {
+        let tape_pos = fields[4].split('+').collect::<Vec<&str>>();
+        if tape_pos.len() != 2 {
+            return Err(format!("malformed tape position field: {:?}", fields[4]));
         }
+        (tape_pos[0].to_string(), tape_pos[1].to_string())
+    } else {
+        ("0".to_string(), "0".to_string())
+    };
+
+    let size = fields[2].parse().map_err(|e| format!("invalid size {:?}: {}", fields[2], e))?;
+    let tape_num = tape_num.parse().map_err(|e| format!("invalid tape_num {:?}: {}", tape_num, e))?;
+    let tape_offset = tape_offset.parse().map_err(|e| format!("invalid tape_offset {:?}: {}", tape_offset, e))?;
+
+    Ok(Some(HpssFile {
+        hpss_path: fields[1].to_string(),
+        size,
+        tape,
+        tape_num,
+        tape_offset,
+    }))
+}
 
-        // if the tape is specified, use it, otherwise call it "0"
-        let tape = if fields[5].len() < 3 { "0" } else { &fields[5] };
+fn parse_tape_metadata(file_metadata: Vec<String>) -> (Vec<HpssFile>, Vec<RejectedMetadataLine>) {
+    // parse the metadata lines
+    info!(line_count = file_metadata.len(), "Parsing hsi metadata into HpssFile objects");
+    let mut hpss_files = Vec::new();
+    let mut rejected = Vec::new();
 
-        // if fields[4] has a + we've got tape number and offset, otherwise call them "0"
-        let mut tape_num = String::from("0");
-        let mut tape_offset = String::from("0");
-        if fields[4].contains('+') {
-            let tape_pos = fields[4].split("+").map(|s| s.to_string()).collect::<Vec<String>>();
-            tape_num = tape_pos[0].clone();
-            tape_offset = tape_pos[1].clone();
+    for metadata in file_metadata {
+        match parse_metadata_line(&metadata) {
+            Ok(Some(hpss_file)) => hpss_files.push(hpss_file),
+            Ok(None) => {}, // the echoed command, not a FILE response; ignore it
+            Err(reason) => {
+                warn!("Quarantining unparseable hsi metadata line ({}): {}", reason, metadata);
+                rejected.push(RejectedMetadataLine { line: metadata, reason });
+            },
         }
-
-        // add this file to the list of files we need to copy
-        hpss_files.push(HpssFile {
-            hpss_path: fields[1].clone(),
-            size: fields[2].parse().unwrap(),
-            tape: String::from(tape),
-            tape_num: tape_num.parse().unwrap(),
-            tape_offset: tape_offset.parse().unwrap(),
-        });
     }
 
-    // return the list of files we need to copy to the caller
-    hpss_files
+    info!(matched_files = hpss_files.len(), rejected_count = rejected.len(), "hsi metadata parsing complete");
+    // return the files we need to copy, plus anything we had to skip
+    (hpss_files, rejected)
+}
+
+/// Write every metadata line that failed to parse alongside the generated
+/// work units, so a corrupt tape record becomes a quarantined line instead
+/// of losing the whole request's worth of files.
+fn write_rejected_metadata_report(work_dir: &PathBuf, request_id: Uuid, rejected: &[RejectedMetadataLine]) {
+    let report_path = work_dir.join(format!("{}-rejected.json", request_id));
+    warn!("{} hsi metadata line(s) failed to parse for request {}; writing rejected-files report to {}", rejected.len(), request_id, report_path.display());
+    match File::create(&report_path) {
+        Ok(file) => {
+            if let Err(e) = serde_json::to_writer_pretty(file, rejected) {
+                error!("Unable to write rejected-files report to {}: {}", report_path.display(), e);
+            }
+        },
+        Err(e) => error!("Unable to create rejected-files report at {}: {}", report_path.display(), e),
+    }
 }
 
 fn group_files_by_tape(hpss_files: &mut Vec<HpssFile>) -> Vec<Vec<HpssFile>> {
-    info!("Grouping {} HpssFile objects into tape groups", hpss_files.len());
+    info!(file_count = hpss_files.len(), "Grouping HpssFile objects into tape groups");
 
     // sort the vector by tape, tape_num, tape_offset, hpss_path
     hpss_files.sort_by(|a, b| {
@@ -261,51 +456,272 @@ fn group_files_by_tape(hpss_files: &mut Vec<HpssFile>) -> Vec<Vec<HpssFile>> {
         grouped.push(current_group);
     }
 
+    info!(tape_group_count = grouped.len(), "tape grouping complete");
     // return the tape-grouped files
     grouped
 }
 
-fn generate_work_units(request: &TaccSyncRequest, tape_groups: &Vec<Vec<HpssFile>>, work_dir: &PathBuf) {
+fn generate_work_units(request: &TaccSyncRequest, tape_groups: &Vec<Vec<HpssFile>>, work_dir: &PathBuf, lease_dir: &PathBuf, max_work_unit_bytes: u64, max_work_unit_files: usize) {
     // generate work units in the work directory
-    info!("Generating {} work units in work directory: {}", tape_groups.len(), work_dir.display());
+    info!(tape_group_count = tape_groups.len(), work_dir = %work_dir.display(), "Generating work units");
+
+    // a prior cycle may have been killed after committing some of these
+    // groups but before the request left the inbox; skip groups the lease
+    // already says were written rather than re-emitting them with a new
+    // work_id
+    let lease = tacc_sync::lease::read(lease_dir, request.request_id).unwrap_or_else(|e| {
+        error!("Unable to read lease for request {}: {}; assuming no groups were committed yet", request.request_id, e);
+        tacc_sync::lease::Lease::default()
+    });
+    let already_committed: std::collections::HashSet<String> = lease.groups.iter().map(|g| g.group_key.clone()).collect();
+
     for (index, tape_group) in tape_groups.iter().enumerate() {
-        // log about what we're processing
-        let mut size = 0;
-        for file in tape_group {
-            size += file.size;
+        // a tape holding more than MAX_WORK_UNIT_BYTES/MAX_WORK_UNIT_FILES
+        // worth of files becomes several sequential work units instead of
+        // one monolithic one, so downstream stages can run them in parallel
+        // and checkpoint at sub-tape granularity. tape_group is already
+        // sorted by tape_num/tape_offset, and split_into_units preserves
+        // that order within and across the units it produces
+        let units = split_into_units(tape_group, max_work_unit_bytes, max_work_unit_files);
+        info!(index = index+1, total = tape_groups.len(), tape = %tape_group[0].tape, file_count = tape_group.len(), unit_count = units.len(), "Processing tape group");
+
+        for unit_files in &units {
+            let size: u64 = unit_files.iter().map(|f| f.size).sum();
+            let hpss_paths: Vec<String> = unit_files.iter().map(|f| f.hpss_path.clone()).collect();
+            let group_key = tacc_sync::lease::group_key(&tape_group[0].tape, &hpss_paths);
+
+            if already_committed.contains(&group_key) {
+                info!(tape = %tape_group[0].tape, "Skipping work unit already committed by a previous cycle");
+                continue;
+            }
+
+            // for each HpssFile in this unit
+            let mut tacc_sync_files = Vec::new();
+            for hpss_file in unit_files {
+                // create a TaccSyncFile for that HpssFile
+                let path = Path::new(&hpss_file.hpss_path);
+                let file_name = path.file_name().expect("Unable to get file_name from hpss_path");
+                tacc_sync_files.push(TaccSyncFile {
+                    file_name: file_name.to_string_lossy().to_string(),
+                    hpss_path: hpss_file.hpss_path.clone(),
+                    size: hpss_file.size,
+                    tape_num: hpss_file.tape_num,
+                    tape_offset: hpss_file.tape_offset,
+                    checksum: None,
+                    attempt_count: 0,
+                    last_error: None,
+                    retry_after: None,
+                    globus_task_id: None,
+                });
+            }
+
+            // create a TaccSyncWork work unit for this slice of the tape group
+            let tacc_sync_work = TaccSyncWork {
+                date_created: Utc::now(),
+                files: tacc_sync_files,
+                request_id: request.request_id,
+                size,
+                tape: tape_group[0].tape.clone(),
+                work_id: Uuid::new_v4(),
+                transfer_id: None,
+                phase: WorkPhase::Requested,
+                reaped_at: None,
+                schema_version: tacc_sync::WORK_SCHEMA_VERSION,
+            };
+
+            // write the work unit; atomic_write_json's temp-file-plus-rename
+            // is our commit point, so a work file downstream stages can see
+            // is never half-written
+            let work_unit_path = work_dir.join(format!("{}.json", tacc_sync_work.work_id));
+            info!("Writing work unit to {}", work_unit_path.display());
+            if let Err(e) = tacc_sync::atomic_write_json(&tacc_sync_work, &work_unit_path) {
+                error!("Unable to write work unit {}: {}", work_unit_path.display(), e);
+                continue;
+            }
+
+            // the unit is now durably committed; record it in the lease so a
+            // killed-and-restarted cycle skips it next time
+            if let Err(e) = tacc_sync::lease::record(lease_dir, request.request_id, &group_key, &tape_group[0].tape, tacc_sync_work.work_id) {
+                error!("Unable to record lease entry for request {} group {}: {}", request.request_id, group_key, e);
+            }
         }
-        info!("Processing {}/{}: {} ({} files - {} bytes)", index+1, tape_groups.len(), tape_group[0].tape, tape_group.len(), size);
-
-        // for each HpssFile in this tape group
-        let mut tacc_sync_files = Vec::new();
-        for hpss_file in tape_group {
-            // create a TaccSyncFile for that HpssFile
-            let path = Path::new(&hpss_file.hpss_path);
-            let file_name = path.file_name().expect("Unable to get file_name from hpss_path");
-            tacc_sync_files.push(TaccSyncFile {
-                file_name: file_name.to_string_lossy().to_string(),
-                hpss_path: hpss_file.hpss_path.clone(),
-                size: hpss_file.size,
-                tape_num: hpss_file.tape_num,
-                tape_offset: hpss_file.tape_offset,
-            });
+    }
+}
+
+/// Split one tape group into contiguous slices, each respecting
+/// `max_bytes` and `max_files`, without reordering the files (the caller
+/// has already sorted the group by `tape_num`/`tape_offset`, and staging
+/// from tape needs to stay sequential). A file larger than `max_bytes` on
+/// its own still lands in a singleton unit rather than being dropped.
+fn split_into_units(tape_group: &[HpssFile], max_bytes: u64, max_files: usize) -> Vec<Vec<HpssFile>> {
+    let mut units = Vec::new();
+    let mut current: Vec<HpssFile> = Vec::new();
+    let mut current_bytes: u64 = 0;
+
+    for file in tape_group {
+        let exceeds_bytes = !current.is_empty() && current_bytes + file.size > max_bytes;
+        let exceeds_files = !current.is_empty() && current.len() + 1 > max_files;
+        if exceeds_bytes || exceeds_files {
+            units.push(std::mem::take(&mut current));
+            current_bytes = 0;
         }
+        current_bytes += file.size;
+        current.push(file.clone());
+    }
+
+    if !current.is_empty() {
+        units.push(current);
+    }
+
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_metadata_line() -> String {
+        "FILE\t/home/projects/icecube/data/exp/IceCube/2011/unbiased/PFRaw/1109/b26eac34-7848-49de-a7c2-193e954af803.zip\t568860644320\t568860644320\t119+558936243566\tAU031800,AU031900\t12\t0\t1\t04/07/2017\t02:19:14\t04/07/2017\t03:07:47".to_string()
+    }
+
+    #[test]
+    fn parse_metadata_line_parses_a_well_formed_file_response() {
+        let hpss_file = parse_metadata_line(&sample_metadata_line()).unwrap().unwrap();
+
+        assert_eq!(hpss_file.hpss_path, "/home/projects/icecube/data/exp/IceCube/2011/unbiased/PFRaw/1109/b26eac34-7848-49de-a7c2-193e954af803.zip");
+        assert_eq!(hpss_file.size, 568860644320);
+        assert_eq!(hpss_file.tape, "AU031800,AU031900");
+        assert_eq!(hpss_file.tape_num, 119);
+        assert_eq!(hpss_file.tape_offset, 558936243566);
+    }
 
-        // create a TaccSyncWork work unit for this tape group
-        let tacc_sync_work = TaccSyncWork {
-            date_created: Utc::now(),
-            files: tacc_sync_files,
-            request_id: request.request_id,
+    #[test]
+    fn parse_metadata_line_ignores_the_echoed_command() {
+        let echoed = "ls -NP /home/projects/icecube/data/exp/IceCube/2009/unbiased/PFRaw/0101/cd88bb827ab811eba0ccfac645b4ea48.zip";
+
+        assert_eq!(parse_metadata_line(echoed).unwrap(), None);
+    }
+
+    #[test]
+    fn parse_metadata_line_rejects_wrong_field_count() {
+        let malformed = "FILE\t/some/path\t123";
+
+        assert!(parse_metadata_line(malformed).is_err());
+    }
+
+    #[test]
+    fn parse_metadata_line_rejects_malformed_tape_position() {
+        let line = sample_metadata_line();
+        let mut fields: Vec<&str> = line.split('\t').collect();
+        let malformed_position = "119+558936243566+extra".to_string();
+        fields[4] = &malformed_position;
+        let malformed = fields.join("\t");
+
+        assert!(parse_metadata_line(&malformed).is_err());
+    }
+
+    #[test]
+    fn parse_metadata_line_defaults_tape_num_and_offset_without_a_plus() {
+        let sample = sample_metadata_line();
+        let mut fields: Vec<&str> = sample.split('\t').collect();
+        fields[4] = "0";
+        let line = fields.join("\t");
+
+        let hpss_file = parse_metadata_line(&line).unwrap().unwrap();
+
+        assert_eq!(hpss_file.tape_num, 0);
+        assert_eq!(hpss_file.tape_offset, 0);
+    }
+
+    #[test]
+    fn parse_tape_metadata_separates_good_lines_from_rejected_ones() {
+        let lines = vec![
+            sample_metadata_line(),
+            "FILE\ttoo\tfew\tfields".to_string(),
+            "ls -NP /some/echoed/command".to_string(),
+        ];
+
+        let (hpss_files, rejected) = parse_tape_metadata(lines);
+
+        assert_eq!(hpss_files.len(), 1);
+        assert_eq!(rejected.len(), 1);
+    }
+
+    #[test]
+    fn filter_request_files_matches_the_glob_pattern() {
+        let paths = vec![
+            "/data/exp/foo.zip".to_string(),
+            "/data/exp/bar.txt".to_string(),
+        ];
+
+        let matched = filter_request_files(paths, "/data/exp/*.zip");
+
+        assert_eq!(matched, vec!["/data/exp/foo.zip".to_string()]);
+    }
+
+    fn hpss_file(tape: &str, tape_num: u64, tape_offset: u64, path: &str, size: u64) -> HpssFile {
+        HpssFile {
+            hpss_path: path.to_string(),
             size,
-            tape: tape_group[0].tape.clone(),
-            work_id: Uuid::new_v4(),
-            transfer_id: None,
-        };
-
-        // write the work unit for this tape group
-        let work_unit_path = work_dir.join(format!("{}.json", tacc_sync_work.work_id));
-        info!("Writing work unit to {}", work_unit_path.display());
-        let file = File::create(work_unit_path).expect("Unable to create file for work unit");
-        serde_json::to_writer_pretty(file, &tacc_sync_work).expect("Unable to write JSON to work unit file");
+            tape: tape.to_string(),
+            tape_num,
+            tape_offset,
+        }
+    }
+
+    #[test]
+    fn group_files_by_tape_groups_contiguous_tapes_together() {
+        let mut files = vec![
+            hpss_file("TAPE002", 0, 0, "/b.dat", 10),
+            hpss_file("TAPE001", 0, 0, "/a.dat", 10),
+            hpss_file("TAPE001", 0, 1, "/c.dat", 10),
+        ];
+
+        let groups = group_files_by_tape(&mut files);
+
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].len(), 2);
+        assert_eq!(groups[0][0].tape, "TAPE001");
+        assert_eq!(groups[1].len(), 1);
+        assert_eq!(groups[1][0].tape, "TAPE002");
+    }
+
+    #[test]
+    fn split_into_units_splits_on_max_bytes() {
+        let group = vec![
+            hpss_file("TAPE001", 0, 0, "/a.dat", 60),
+            hpss_file("TAPE001", 0, 1, "/b.dat", 60),
+        ];
+
+        let units = split_into_units(&group, 100, usize::MAX);
+
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].len(), 1);
+        assert_eq!(units[1].len(), 1);
+    }
+
+    #[test]
+    fn split_into_units_splits_on_max_files() {
+        let group = vec![
+            hpss_file("TAPE001", 0, 0, "/a.dat", 1),
+            hpss_file("TAPE001", 0, 1, "/b.dat", 1),
+            hpss_file("TAPE001", 0, 2, "/c.dat", 1),
+        ];
+
+        let units = split_into_units(&group, u64::MAX, 2);
+
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].len(), 2);
+        assert_eq!(units[1].len(), 1);
+    }
+
+    #[test]
+    fn split_into_units_never_drops_an_oversized_singleton() {
+        let group = vec![hpss_file("TAPE001", 0, 0, "/a.dat", 1000)];
+
+        let units = split_into_units(&group, 10, usize::MAX);
+
+        assert_eq!(units.len(), 1);
+        assert_eq!(units[0].len(), 1);
     }
 }
@@ -0,0 +1,314 @@
+// daemon.rs
+//
+// The common shape shared by every polling pipeline stage: read a handful
+// of env vars, poll an inbox directory for JSON work units, process each
+// one, route it to the outbox/quarantine, honor RUN_ONCE_AND_DIE, sleep
+// WORK_SLEEP_SECONDS, and manage a PID file. `run_daemon` owns that shape
+// so a new stage only has to implement `Worker::process`.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+use tracing::{error, info, info_span};
+
+use crate::tasklog::TaskLogGuard;
+use crate::{boolify, clean_up_and_exit, find_json_files_in_directory, load_work_from_file, move_to_outbox, TaccSyncWork};
+
+/// the process exit code indicating successful exit
+const EXIT_SUCCESS: i32 = 0;
+
+/// WorkOutcome tells `run_daemon` what to do with a work unit after a
+/// `Worker` has processed it.
+pub enum WorkOutcome {
+    /// the work unit is done with this stage; move it to the outbox
+    Advance,
+    /// something is wrong with the work unit; move it to quarantine
+    Quarantine,
+    /// the work unit isn't ready yet; leave it in the inbox for the next cycle
+    Retry,
+}
+
+/// Worker is implemented by each pipeline stage to describe what it does
+/// with one work unit. `run_daemon` owns the poll loop, PID lifecycle,
+/// sleep/run-once logic, inbox routing, and concurrency limiting around it.
+///
+/// `process` takes `work` by mutable reference and is handed the inbox path
+/// it was loaded from, so a stage that needs to checkpoint a phase change
+/// mid-processing (e.g. before a destructive step) can do so with
+/// `atomic_write_json(work, json_file)` before returning. `run_daemon` may
+/// call `process` from several worker threads at once (see
+/// `DaemonConfig::max_concurrency`), so implementations must be `Sync`.
+pub trait Worker: Sync {
+    fn process(&self, work: &mut TaccSyncWork, json_file: &Path) -> WorkOutcome;
+}
+
+/// DaemonConfigError represents a failure parsing a `DaemonConfig` from the
+/// environment.
+#[derive(Debug)]
+pub enum DaemonConfigError {
+    MissingVar(String),
+    InvalidInt(String, std::num::ParseIntError),
+}
+
+impl fmt::Display for DaemonConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonConfigError::MissingVar(name) => write!(f, "{} environment variable not set", name),
+            DaemonConfigError::InvalidInt(name, e) => write!(f, "{} environment variable must be an integer: {}", name, e),
+        }
+    }
+}
+
+impl std::error::Error for DaemonConfigError {}
+
+/// DaemonConfig centralizes the env vars common to every polling pipeline
+/// stage, so each stage's `main` only has to parse the env vars specific to
+/// itself.
+pub struct DaemonConfig {
+    pub inbox_dir: PathBuf,
+    pub outbox_dir: PathBuf,
+    pub quarantine_dir: PathBuf,
+    pub pid_path: String,
+    pub run_once: bool,
+    pub sleep_seconds: u64,
+    /// where per-work-unit log files are written while a unit is in flight
+    pub task_log_dir: PathBuf,
+    /// the maximum number of work units `run_daemon` will process at once;
+    /// defaults to 1 (strictly sequential) when MAX_CONCURRENCY isn't set
+    pub max_concurrency: usize,
+}
+
+impl DaemonConfig {
+    /// Load a `DaemonConfig` from the standard INBOX_DIR/OUTBOX_DIR/
+    /// QUARANTINE_DIR/PID_PATH/RUN_ONCE_AND_DIE/WORK_SLEEP_SECONDS/
+    /// TASK_LOG_DIR environment variables, plus the optional
+    /// MAX_CONCURRENCY.
+    pub fn from_env() -> Result<Self, DaemonConfigError> {
+        let inbox_dir = env_var("INBOX_DIR")?;
+        let outbox_dir = env_var("OUTBOX_DIR")?;
+        let quarantine_dir = env_var("QUARANTINE_DIR")?;
+        let pid_path = env_var("PID_PATH")?;
+        let run_once_and_die = env_var("RUN_ONCE_AND_DIE")?;
+        let work_sleep_seconds = env_var("WORK_SLEEP_SECONDS")?;
+        let task_log_dir = env_var("TASK_LOG_DIR")?;
+
+        let sleep_seconds = work_sleep_seconds
+            .parse::<u64>()
+            .map_err(|e| DaemonConfigError::InvalidInt("WORK_SLEEP_SECONDS".to_string(), e))?;
+
+        let max_concurrency = match std::env::var("MAX_CONCURRENCY") {
+            Ok(value) => value
+                .parse::<usize>()
+                .map_err(|e| DaemonConfigError::InvalidInt("MAX_CONCURRENCY".to_string(), e))?,
+            Err(_) => 1,
+        };
+
+        Ok(DaemonConfig {
+            inbox_dir: PathBuf::from(inbox_dir),
+            outbox_dir: PathBuf::from(outbox_dir),
+            quarantine_dir: PathBuf::from(quarantine_dir),
+            pid_path,
+            run_once: boolify(&run_once_and_die),
+            sleep_seconds,
+            task_log_dir: PathBuf::from(task_log_dir),
+            max_concurrency,
+        })
+    }
+}
+
+fn env_var(name: &str) -> Result<String, DaemonConfigError> {
+    std::env::var(name).map_err(|_| DaemonConfigError::MissingVar(name.to_string()))
+}
+
+/// Install SIGTERM/SIGINT handlers that flip the returned flag instead of
+/// killing the process immediately, so `run_daemon` can finish whatever
+/// work units are already in flight and checkpoint them safely before it
+/// actually exits.
+pub fn install_shutdown_flag() -> Arc<AtomicBool> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGTERM, signal_hook::consts::SIGINT] {
+        if let Err(e) = signal_hook::flag::register(signal, Arc::clone(&shutdown)) {
+            error!("Unable to register shutdown handler for signal {}: {}", signal, e);
+        }
+    }
+    shutdown
+}
+
+/// A fixed set of tokens shared across worker threads within one cycle, so
+/// at most `n` units of work are ever in flight regardless of how many the
+/// cycle found: `acquire` blocks until a token is available (providing
+/// backpressure on whatever loop is dispatching work), and a clone of
+/// `returns` lets whoever is holding a token give it back when they're done.
+/// Generic enough that other stages with their own dispatch loop reuse it
+/// too (see the syncer's and finisher's request dispatch).
+pub struct TokenPool {
+    tokens: mpsc::Receiver<()>,
+    returns: mpsc::Sender<()>,
+}
+
+impl TokenPool {
+    pub fn new(n: usize) -> Self {
+        let (returns, tokens) = mpsc::channel();
+        for _ in 0..n {
+            returns.send(()).expect("token channel just created");
+        }
+        TokenPool { tokens, returns }
+    }
+
+    /// Block until a token is available. Only ever called from the
+    /// dispatching thread, so `tokens` itself is never shared.
+    pub fn acquire(&self) {
+        self.tokens.recv().expect("a token sender outlives every acquire");
+    }
+
+    pub fn returns(&self) -> mpsc::Sender<()> {
+        self.returns.clone()
+    }
+}
+
+/// Load and process one work unit, returning the outcome plus its work_id
+/// (if loading got far enough to learn it), so the caller can route its
+/// JSON and task log once this runs back on the dispatching thread.
+fn process_one<W: Worker>(worker: &W, json_file: &PathBuf, task_log_dir: &Path) -> (WorkOutcome, Option<String>) {
+    let mut work = match load_work_from_file(json_file) {
+        Ok(work) => work,
+        Err(e) => {
+            error!("Unable to load TaccSyncWork: {}: {}", json_file.display(), e);
+            return (WorkOutcome::Quarantine, None);
+        },
+    };
+
+    let work_id = work.work_id.to_string();
+    let span = info_span!("work_unit", work_id = %work.work_id, request_id = %work.request_id, tape = %work.tape);
+    let span_guard = span.enter();
+    let log_guard = TaskLogGuard::open(task_log_dir, &work_id)
+        .map_err(|e| error!("Unable to open per-work-unit log file for {}: {}", work_id, e))
+        .ok();
+
+    let outcome = worker.process(&mut work, json_file);
+
+    // drop the guards so the log file is flushed and closed before we try to move it
+    drop(log_guard);
+    drop(span_guard);
+
+    (outcome, Some(work_id))
+}
+
+/// Run the standard inbox-poll-process-route loop for `worker`, using
+/// `config` for the shared PID/sleep/run-once/directory/concurrency
+/// behavior.
+///
+/// Up to `config.max_concurrency` work units are processed at once, each on
+/// its own worker thread gated by a token from a fixed-size `TokenPool`.
+/// Routing (`move_to_outbox` and the task log move) happens back on this
+/// thread as results arrive, so it stays serialized per destination no
+/// matter how many units ran concurrently.
+///
+/// This never returns when `config.run_once` is `false` and `shutdown` is
+/// never flagged; when either is true it waits for every in-flight unit to
+/// finish, then exits via `clean_up_and_exit`. `shutdown` is checked both
+/// between dispatching new units and while sleeping, so a stage doesn't sit
+/// on a signal for up to `config.sleep_seconds` before noticing it.
+pub fn run_daemon<W: Worker + Send + Sync + 'static>(worker: W, config: DaemonConfig, shutdown: Arc<AtomicBool>) {
+    let worker = Arc::new(worker);
+    let max_concurrency = config.max_concurrency.max(1);
+
+    loop {
+        info!("Starting work cycle");
+
+        // search the inbox for work to do
+        info!("Checking for work in inbox directory: {}", config.inbox_dir.display());
+        let json_files = find_json_files_in_directory(&config.inbox_dir.to_string_lossy());
+        let num_files = json_files.len();
+
+        // dispatch each unit of work onto up to max_concurrency worker threads
+        info!("Processing {} work units with up to {} in flight", num_files, max_concurrency);
+        let tokens = TokenPool::new(max_concurrency);
+        let (result_tx, result_rx) = mpsc::channel();
+        let mut handles = Vec::with_capacity(num_files);
+
+        for (index, json_file) in json_files.into_iter().enumerate() {
+            // a shutdown signal leaves any not-yet-started units in the
+            // inbox for the next start, rather than picking up new work
+            if shutdown.load(Ordering::Relaxed) {
+                info!("Shutdown requested; leaving {} remaining work unit(s) in the inbox for next start", num_files - index);
+                break;
+            }
+
+            // block here until a token frees up, so at most max_concurrency
+            // units are ever in flight at once
+            tokens.acquire();
+
+            let worker = Arc::clone(&worker);
+            let task_log_dir = config.task_log_dir.clone();
+            let result_tx = result_tx.clone();
+            let release = tokens.returns();
+
+            handles.push(thread::spawn(move || {
+                info!("Processing {}/{}: {}", index + 1, num_files, json_file.display());
+                let (outcome, work_id) = process_one(&*worker, &json_file, &task_log_dir);
+                let _ = release.send(());
+                let _ = result_tx.send((json_file, work_id, outcome));
+            }));
+        }
+        // drop our own sender so the result_rx loop below ends once every
+        // spawned thread's cloned sender has, in turn, been dropped
+        drop(result_tx);
+
+        // route results on this thread as they arrive, so move_to_outbox
+        // stays serialized per destination even though processing ran concurrently
+        for (json_file, work_id, outcome) in result_rx {
+            match outcome {
+                WorkOutcome::Advance => {
+                    let _ = move_to_outbox(&json_file, &config.outbox_dir);
+                    if let Some(work_id) = &work_id {
+                        TaskLogGuard::move_to(&config.task_log_dir, work_id, &config.outbox_dir);
+                    }
+                },
+                WorkOutcome::Quarantine => {
+                    let _ = move_to_outbox(&json_file, &config.quarantine_dir);
+                    if let Some(work_id) = &work_id {
+                        TaskLogGuard::move_to(&config.task_log_dir, work_id, &config.quarantine_dir);
+                    }
+                },
+                WorkOutcome::Retry => {
+                    info!("Work unit {} is not ready; will check again next cycle", json_file.display());
+                },
+            }
+        }
+
+        // RUN_ONCE_AND_DIE (and a shutdown signal) must not exit until every
+        // in-flight unit has actually finished, not merely reported its outcome
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        // if this was a one-shot adventure
+        if config.run_once {
+            info!("RUN_ONCE_AND_DIE -- daemon now ending");
+            clean_up_and_exit(&config.pid_path, EXIT_SUCCESS);
+        }
+
+        // a shutdown signal received during this cycle: every in-flight
+        // unit above has already been checkpointed and routed, so it's
+        // safe to exit now rather than starting another cycle
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Shutdown requested -- daemon now ending");
+            clean_up_and_exit(&config.pid_path, EXIT_SUCCESS);
+        }
+
+        // otherwise, sleep until we need to wake up again, checking once a
+        // second so a shutdown signal doesn't sit unnoticed for the full interval
+        info!("Sleeping for {} seconds...", config.sleep_seconds);
+        for _ in 0..config.sleep_seconds {
+            if shutdown.load(Ordering::Relaxed) {
+                break;
+            }
+            sleep(Duration::from_secs(1));
+        }
+    }
+}
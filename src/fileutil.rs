@@ -0,0 +1,146 @@
+// fileutil.rs
+//
+// Durable, crash-safe file operations. The inbox/outbox/quarantine/transfer
+// directories used across the pipeline may live on different mounts, so a
+// plain `fs::rename` can fail with EXDEV, and a plain `File::create` can
+// leave a half-written file behind if the process dies mid-write. The
+// helpers here make both of those operations safe to interrupt.
+
+use serde::Serialize;
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// the errno Linux uses for "Invalid cross-device link", returned by
+/// `rename(2)` when the source and destination are on different filesystems
+const EXDEV: i32 = 18;
+
+/// FileUtilError represents a failure in one of the durable file operations
+/// in this module
+#[derive(Debug)]
+pub enum FileUtilError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for FileUtilError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileUtilError::Io(e) => write!(f, "I/O error: {}", e),
+            FileUtilError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FileUtilError {}
+
+impl From<io::Error> for FileUtilError {
+    fn from(e: io::Error) -> Self {
+        FileUtilError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for FileUtilError {
+    fn from(e: serde_json::Error) -> Self {
+        FileUtilError::Json(e)
+    }
+}
+
+/// fsync the file at `path` so its contents are durable on disk.
+fn fsync_path(path: &Path) -> Result<(), FileUtilError> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// fsync the directory at `path` so that renames/creates within it are
+/// durable, not just the files themselves.
+fn fsync_dir(path: &Path) -> Result<(), FileUtilError> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+/// Copy `src` to `dest`, fsync it, then remove `src`. Used as the fallback
+/// when `rename` can't be used because `src` and `dest` are on different
+/// filesystems (EXDEV).
+fn copy_then_remove(src: &Path, dest: &Path) -> Result<(), FileUtilError> {
+    fs::copy(src, dest)?;
+    fsync_path(dest)?;
+    fs::remove_file(src)?;
+    Ok(())
+}
+
+/// Durably move `src` into `dest_dir`, surviving both a crash mid-move and a
+/// `src`/`dest_dir` pair that live on different filesystems.
+///
+/// The file is first staged at `<dest_dir>/<name>.tmp-<pid>` (via `rename`
+/// when possible, falling back to a streamed copy on `EXDEV`), fsynced, then
+/// `rename`d into its final name within `dest_dir` -- the only step visible
+/// to a concurrent reader of `dest_dir`. The directory is fsynced afterward
+/// so the rename itself is durable.
+///
+/// # Arguments
+///
+/// * `src` - the file to move
+/// * `dest_dir` - the directory to move it into
+///
+/// # Returns
+///
+/// A `Result` containing the final path of the moved file on success.
+pub fn durable_move(src: &Path, dest_dir: &Path) -> Result<PathBuf, FileUtilError> {
+    let file_name = src.file_name().ok_or_else(|| {
+        FileUtilError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", src.display()),
+        ))
+    })?;
+
+    let tmp_path = dest_dir.join(format!("{}.tmp-{}", file_name.to_string_lossy(), std::process::id()));
+    let dest_path = dest_dir.join(file_name);
+
+    match fs::rename(src, &tmp_path) {
+        Ok(()) => {}
+        Err(e) if e.raw_os_error() == Some(EXDEV) => {
+            copy_then_remove(src, &tmp_path)?;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    fsync_path(&tmp_path)?;
+    fs::rename(&tmp_path, &dest_path)?;
+    fsync_dir(dest_dir)?;
+
+    Ok(dest_path)
+}
+
+/// Serialize `value` to `dest_path` as pretty JSON without ever leaving a
+/// half-written file at `dest_path` for another stage to pick up. The value
+/// is written to a temporary file alongside `dest_path`, fsynced, then
+/// renamed into place; the parent directory is fsynced afterward so the
+/// rename survives a crash.
+///
+/// # Arguments
+///
+/// * `value` - the value to serialize, e.g. a `TaccSyncWork` or `TaccSyncRequest`
+/// * `dest_path` - the final path the JSON should be written to
+pub fn atomic_write_json<T: Serialize>(value: &T, dest_path: &Path) -> Result<(), FileUtilError> {
+    let dest_dir = dest_path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = dest_path.file_name().ok_or_else(|| {
+        FileUtilError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no file name", dest_path.display()),
+        ))
+    })?;
+    let tmp_path = dest_dir.join(format!("{}.tmp-{}", file_name.to_string_lossy(), std::process::id()));
+
+    {
+        let file = File::create(&tmp_path)?;
+        serde_json::to_writer_pretty(&file, value)?;
+        file.sync_all()?;
+    }
+
+    fs::rename(&tmp_path, dest_path)?;
+    fsync_dir(dest_dir)?;
+
+    Ok(())
+}
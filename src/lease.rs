@@ -0,0 +1,183 @@
+// lease.rs
+//
+// Crash-safe checkpointing for the syncer's work-unit generation. After
+// `generate_work_units` commits a tape group to `work_dir`, it records the
+// group in a lease file keyed by `request_id`, so a syncer that's killed
+// mid-cycle and restarted can tell which groups it already wrote and skip
+// them instead of re-emitting duplicate `TaccSyncWork` files with fresh
+// `work_id`s. The restarted cycle still re-runs the hsi queries. The lease also lets the
+// finisher tell its own work apart from another request's when a work file
+// in hpss/globus/reaper fails to load mid-scan. It is cleared once the
+// finisher has confirmed the request is completely done.
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::fileutil::FileUtilError;
+
+/// LeaseError represents a failure reading or writing a request's lease file.
+#[derive(Debug)]
+pub enum LeaseError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeaseError::Io(e) => write!(f, "I/O error: {}", e),
+            LeaseError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {}
+
+impl From<io::Error> for LeaseError {
+    fn from(e: io::Error) -> Self {
+        LeaseError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LeaseError {
+    fn from(e: serde_json::Error) -> Self {
+        LeaseError::Json(e)
+    }
+}
+
+impl From<FileUtilError> for LeaseError {
+    fn from(e: FileUtilError) -> Self {
+        match e {
+            FileUtilError::Io(e) => LeaseError::Io(e),
+            FileUtilError::Json(e) => LeaseError::Json(e),
+        }
+    }
+}
+
+/// One tape group already committed to `work_dir` as a `TaccSyncWork` unit.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LeasedGroup {
+    pub group_key: String,
+    pub work_id: Uuid,
+    pub tape: String,
+}
+
+/// Lease is the full checkpoint record for one request's in-progress (or
+/// already-finished) work-unit generation.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct Lease {
+    #[serde(default)]
+    pub groups: Vec<LeasedGroup>,
+}
+
+fn lease_path(lease_dir: &Path, request_id: Uuid) -> PathBuf {
+    lease_dir.join(format!("{}.json", request_id))
+}
+
+/// Deterministic key for a tape group: its tape label plus a hash of its
+/// member `hpss_path`s, sorted first so member order never changes the key.
+pub fn group_key(tape: &str, hpss_paths: &[String]) -> String {
+    let mut paths = hpss_paths.to_vec();
+    paths.sort();
+    let mut hasher = DefaultHasher::new();
+    for path in &paths {
+        path.hash(&mut hasher);
+    }
+    format!("{}-{:016x}", tape, hasher.finish())
+}
+
+/// Read the lease for `request_id`, or an empty one if none exists yet.
+pub fn read(lease_dir: &Path, request_id: Uuid) -> Result<Lease, LeaseError> {
+    let path = lease_path(lease_dir, request_id);
+    if !path.exists() {
+        return Ok(Lease::default());
+    }
+    let file = File::open(&path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Record that `group_key` has been committed to `work_dir` as `work_id`.
+pub fn record(lease_dir: &Path, request_id: Uuid, group_key: &str, tape: &str, work_id: Uuid) -> Result<(), LeaseError> {
+    let mut lease = read(lease_dir, request_id)?;
+    lease.groups.push(LeasedGroup {
+        group_key: group_key.to_string(),
+        work_id,
+        tape: tape.to_string(),
+    });
+    crate::atomic_write_json(&lease, &lease_path(lease_dir, request_id))?;
+    Ok(())
+}
+
+/// Remove `request_id`'s lease entirely, once the finisher has confirmed
+/// every work unit it generated has cleared the pipeline.
+pub fn clear(lease_dir: &Path, request_id: Uuid) -> Result<(), LeaseError> {
+    let path = lease_path(lease_dir, request_id);
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_key_is_insensitive_to_path_order() {
+        let paths_a = vec!["/hpss/b.dat".to_string(), "/hpss/a.dat".to_string()];
+        let paths_b = vec!["/hpss/a.dat".to_string(), "/hpss/b.dat".to_string()];
+
+        assert_eq!(group_key("TAPE001", &paths_a), group_key("TAPE001", &paths_b));
+    }
+
+    #[test]
+    fn group_key_differs_by_tape() {
+        let paths = vec!["/hpss/a.dat".to_string()];
+
+        assert_ne!(group_key("TAPE001", &paths), group_key("TAPE002", &paths));
+    }
+
+    #[test]
+    fn group_key_differs_by_membership() {
+        let paths_a = vec!["/hpss/a.dat".to_string()];
+        let paths_b = vec!["/hpss/a.dat".to_string(), "/hpss/b.dat".to_string()];
+
+        assert_ne!(group_key("TAPE001", &paths_a), group_key("TAPE001", &paths_b));
+    }
+
+    #[test]
+    fn read_returns_empty_lease_when_no_file_exists() {
+        let lease_dir = std::env::temp_dir().join(format!("tacc-sync-lease-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&lease_dir).unwrap();
+
+        let lease = read(&lease_dir, Uuid::new_v4()).unwrap();
+
+        assert!(lease.groups.is_empty());
+        let _ = std::fs::remove_dir_all(&lease_dir);
+    }
+
+    #[test]
+    fn record_then_read_round_trips_and_clear_removes_it() {
+        let lease_dir = std::env::temp_dir().join(format!("tacc-sync-lease-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&lease_dir).unwrap();
+        let request_id = Uuid::new_v4();
+        let work_id = Uuid::new_v4();
+
+        record(&lease_dir, request_id, "TAPE001-abc123", "TAPE001", work_id).unwrap();
+        let lease = read(&lease_dir, request_id).unwrap();
+        assert_eq!(lease.groups.len(), 1);
+        assert_eq!(lease.groups[0].work_id, work_id);
+
+        clear(&lease_dir, request_id).unwrap();
+        let lease = read(&lease_dir, request_id).unwrap();
+        assert!(lease.groups.is_empty());
+
+        let _ = std::fs::remove_dir_all(&lease_dir);
+    }
+}
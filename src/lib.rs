@@ -3,14 +3,67 @@
 use chrono::{DateTime, Utc};
 use log::{error, info};
 use serde::{Deserialize, Serialize};
-use serde_json::Result;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::Read;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
+pub mod daemon;
+pub mod fileutil;
+pub mod lease;
+pub mod migrate;
+pub mod registry;
+pub mod status;
+pub mod tasklog;
+pub mod verify;
+
+pub use fileutil::{atomic_write_json, durable_move, FileUtilError};
+pub use migrate::{REQUEST_SCHEMA_VERSION, WORK_SCHEMA_VERSION};
+pub use verify::{verify_work, VerifyError};
+
+/// LoadError represents a failure to load a `TaccSyncRequest`/`TaccSyncWork`
+/// from a JSON file: the file couldn't be read, its contents weren't valid
+/// JSON, or its `schema_version` is newer than this binary understands.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Migration(migrate::MigrationError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "I/O error: {}", e),
+            LoadError::Json(e) => write!(f, "JSON error: {}", e),
+            LoadError::Migration(e) => write!(f, "migration error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LoadError {
+    fn from(e: serde_json::Error) -> Self {
+        LoadError::Json(e)
+    }
+}
+
+impl From<migrate::MigrationError> for LoadError {
+    fn from(e: migrate::MigrationError) -> Self {
+        LoadError::Migration(e)
+    }
+}
+
 /// HpssFile represents the file metadata returned by HPSS
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HpssFile {
     pub hpss_path: String,
     pub size: u64,
@@ -32,6 +85,23 @@ pub struct TaccSyncFile {
     pub tape_num: u64,
     /// how many bytes past the mark the file starts
     pub tape_offset: u64,
+    /// the expected BLAKE3 digest of the file, hex-encoded, if known
+    #[serde(default)]
+    pub checksum: Option<String>,
+    /// how many times a Globus transfer has been attempted for this file
+    #[serde(default)]
+    pub attempt_count: u32,
+    /// the most recent error Globus reported for this file, if any
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// the earliest time a retry should be attempted, if this file is
+    /// currently backing off after a retryable failure
+    #[serde(default)]
+    pub retry_after: Option<DateTime<Utc>>,
+    /// the Globus task id currently transferring this file, if a transfer
+    /// has been submitted and not yet resolved to a terminal state
+    #[serde(default)]
+    pub globus_task_id: Option<Uuid>,
 }
 
 
@@ -45,6 +115,34 @@ pub struct TaccSyncRequest {
     pub source: String,
     pub dest: String,
     pub pattern: String,
+    /// the version of this JSON's schema; see the `migrate` module
+    #[serde(default = "migrate::request_schema_version")]
+    pub schema_version: u32,
+}
+
+/// WorkPhase represents where a TaccSyncWork unit is in the pipeline. Each
+/// stage checkpoints its phase back to the work unit's JSON before
+/// performing its destructive action, so a stage that is killed and
+/// restarted can tell whether that action already happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum WorkPhase {
+    /// the work unit was just generated by the syncer and has not been
+    /// picked up by any downstream stage yet
+    Requested,
+    /// a retriever/globus_xfer stage has started moving the files
+    Transferring,
+    /// all files for this work unit have finished transferring
+    Transferred,
+    /// the reaper has started tearing down the transfer buffer
+    Reaping,
+    /// the work unit is fully processed and ready for the outbox
+    Done,
+}
+
+impl Default for WorkPhase {
+    fn default() -> Self {
+        WorkPhase::Requested
+    }
 }
 
 /// TaccSyncWork represents a tape-grouped set of files to synchronize from NERSC to TACC
@@ -64,6 +162,17 @@ pub struct TaccSyncWork {
     pub files: Vec<TaccSyncFile>,
     /// the Globus transfer id of this work unit, if created
     pub transfer_id: Option<Uuid>,
+    /// where this work unit is in the pipeline; defaults to `Requested` for
+    /// work units generated before this field existed
+    #[serde(default)]
+    pub phase: WorkPhase,
+    /// the timestamp the reaper finished tearing down the transfer buffer
+    /// for this work unit, if it has
+    #[serde(default)]
+    pub reaped_at: Option<DateTime<Utc>>,
+    /// the version of this JSON's schema; see the `migrate` module
+    #[serde(default = "migrate::work_schema_version")]
+    pub schema_version: u32,
 }
 
 /// Converts a string with truthy values into a boolean.
@@ -137,7 +246,9 @@ pub fn find_json_files_in_directory(dir_path: &str) -> Vec<PathBuf> {
     json_files
 }
 
-/// Load a TaccSyncRequest object from a JSON file.
+/// Load a TaccSyncRequest object from a JSON file, migrating it up to
+/// `REQUEST_SCHEMA_VERSION` first if it was written by an older version of
+/// this binary.
 ///
 /// # Arguments
 ///
@@ -146,15 +257,19 @@ pub fn find_json_files_in_directory(dir_path: &str) -> Vec<PathBuf> {
 /// # Returns
 ///
 /// Result containing a TaccSyncRequest object if loading was successful.
-pub fn load_request_from_file(file_path: &PathBuf) -> Result<TaccSyncRequest> {
-    let mut file = File::open(file_path).expect("file not found");
+pub fn load_request_from_file(file_path: &PathBuf) -> std::result::Result<TaccSyncRequest, LoadError> {
+    let mut file = File::open(file_path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents).expect("something went wrong reading the file");
-    let r: TaccSyncRequest = serde_json::from_str(&contents)?;
+    file.read_to_string(&mut contents)?;
+    let value: serde_json::Value = serde_json::from_str(&contents)?;
+    let migrated = migrate::migrate_request(value)?;
+    let r: TaccSyncRequest = serde_json::from_value(migrated)?;
     Ok(r)
 }
 
-/// Load a TaccSyncWork object from a JSON file.
+/// Load a TaccSyncWork object from a JSON file, migrating it up to
+/// `WORK_SCHEMA_VERSION` first if it was written by an older version of
+/// this binary.
 ///
 /// # Arguments
 ///
@@ -163,16 +278,21 @@ pub fn load_request_from_file(file_path: &PathBuf) -> Result<TaccSyncRequest> {
 /// # Returns
 ///
 /// Result containing a TaccSyncWork object if loading was successful.
-pub fn load_work_from_file(file_path: &PathBuf) -> Result<TaccSyncWork> {
-    let mut file = File::open(file_path).expect("file not found");
+pub fn load_work_from_file(file_path: &PathBuf) -> std::result::Result<TaccSyncWork, LoadError> {
+    let mut file = File::open(file_path)?;
     let mut contents = String::new();
-    file.read_to_string(&mut contents).expect("something went wrong reading the file");
-    let r: TaccSyncWork = serde_json::from_str(&contents)?;
+    file.read_to_string(&mut contents)?;
+    let value: serde_json::Value = serde_json::from_str(&contents)?;
+    let migrated = migrate::migrate_work(value)?;
+    let r: TaccSyncWork = serde_json::from_value(migrated)?;
     Ok(r)
 }
 
 /// Moves the provided file to the provided destination directory.
 ///
+/// This is crash-safe and works across filesystem boundaries: see
+/// `fileutil::durable_move` for the mechanics.
+///
 /// # Arguments
 ///
 /// * `file_path` - The path to the file to be moved.
@@ -180,26 +300,12 @@ pub fn load_work_from_file(file_path: &PathBuf) -> Result<TaccSyncWork> {
 ///
 /// # Returns
 ///
-/// A `Result` indicating success or failure.
-pub fn move_to_outbox(file_path: &PathBuf, dest_dir: &PathBuf) {
-    // if we can get the file name of the source file
-    if let Some(file_name) = file_path.file_name() {
-        // construct the destination path by appending the file name to the destination directory
-        let dest_path = dest_dir.join(file_name);
-        // attempt to move the file
-        info!("Moving {} to {}", file_path.display(), dest_path.display());
-        match fs::rename(&file_path, &dest_path) {
-            Err(e) => {
-                // if we can't move a file, better to stop immediately
-                error!("Unable to rename: Unable to move {} to {}", file_path.display(), dest_dir.display());
-                error!("Error: {}", e);
-                panic!("FULL STOP -- Failed to perform basic but critical file system operation")
-            },
-            _ => return
-        }
-    }
-
-    // if we can't move a file, better to stop immediately
-    error!("Missing file_name: Unable to move {} to {}", file_path.display(), dest_dir.display());
-    panic!("FULL STOP -- Failed to perform basic but critical file system operation")
+/// A `Result` indicating success or failure, so callers can decide whether
+/// to quarantine the file, retry, or abort rather than crashing the process.
+pub fn move_to_outbox(file_path: &PathBuf, dest_dir: &PathBuf) -> std::result::Result<(), FileUtilError> {
+    info!("Moving {} to {}", file_path.display(), dest_dir.display());
+    fileutil::durable_move(file_path, dest_dir).map(|_| ()).map_err(|e| {
+        error!("Unable to move {} to {}: {}", file_path.display(), dest_dir.display(), e);
+        e
+    })
 }
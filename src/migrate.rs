@@ -0,0 +1,171 @@
+// migrate.rs
+//
+// TaccSyncWork/TaccSyncRequest JSON already sitting in inboxes across the
+// pipeline must keep loading even after the struct definitions change. Each
+// loader deserializes into a `serde_json::Value` first, reads its
+// `schema_version` (absent means v1), and runs it through an ordered chain
+// of `migrate_vN_to_vN+1` transforms up to the version this binary
+// understands before handing it to serde for the final typed deserialize.
+
+use serde_json::Value;
+use std::fmt;
+
+/// the current schema version for `TaccSyncWork` JSON
+pub const WORK_SCHEMA_VERSION: u32 = 2;
+
+/// the current schema version for `TaccSyncRequest` JSON
+pub const REQUEST_SCHEMA_VERSION: u32 = 1;
+
+/// Returns `WORK_SCHEMA_VERSION`; used as a serde `default =` function so a
+/// freshly-constructed `TaccSyncWork` defaults to the current version.
+pub fn work_schema_version() -> u32 {
+    WORK_SCHEMA_VERSION
+}
+
+/// Returns `REQUEST_SCHEMA_VERSION`; used as a serde `default =` function so
+/// a freshly-constructed `TaccSyncRequest` defaults to the current version.
+pub fn request_schema_version() -> u32 {
+    REQUEST_SCHEMA_VERSION
+}
+
+/// MigrationError represents a failure to bring a JSON value up to the
+/// schema version this binary understands.
+#[derive(Debug)]
+pub enum MigrationError {
+    /// the JSON's schema_version is newer than this binary knows how to read
+    TooNew { found: u32, max_supported: u32 },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::TooNew { found, max_supported } => write!(
+                f,
+                "schema_version {} is newer than the {} this binary supports",
+                found, max_supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+/// Read `schema_version` out of a JSON object, defaulting to 1 for JSON
+/// written before the field existed.
+fn schema_version(value: &Value) -> u32 {
+    value
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map(|v| v as u32)
+        .unwrap_or(1)
+}
+
+/// Migrate a `TaccSyncWork` JSON value up to `WORK_SCHEMA_VERSION`.
+pub fn migrate_work(mut value: Value) -> Result<Value, MigrationError> {
+    let mut version = schema_version(&value);
+    if version > WORK_SCHEMA_VERSION {
+        return Err(MigrationError::TooNew { found: version, max_supported: WORK_SCHEMA_VERSION });
+    }
+
+    if version == 1 {
+        value = migrate_work_v1_to_v2(value);
+        version = 2;
+    }
+
+    set_schema_version(&mut value, version);
+    Ok(value)
+}
+
+/// v1 -> v2: added the `phase`/`reaped_at` resumability fields. Old work
+/// units had neither, and start fresh at the `Requested` phase.
+fn migrate_work_v1_to_v2(mut value: Value) -> Value {
+    if let Value::Object(ref mut map) = value {
+        map.entry("phase".to_string()).or_insert_with(|| Value::String("Requested".to_string()));
+        map.entry("reaped_at".to_string()).or_insert(Value::Null);
+    }
+    value
+}
+
+/// Migrate a `TaccSyncRequest` JSON value up to `REQUEST_SCHEMA_VERSION`.
+pub fn migrate_request(value: Value) -> Result<Value, MigrationError> {
+    let version = schema_version(&value);
+    if version > REQUEST_SCHEMA_VERSION {
+        return Err(MigrationError::TooNew { found: version, max_supported: REQUEST_SCHEMA_VERSION });
+    }
+
+    // no migrations defined yet; v1 is both the floor and the ceiling
+    let mut value = value;
+    set_schema_version(&mut value, REQUEST_SCHEMA_VERSION);
+    Ok(value)
+}
+
+fn set_schema_version(value: &mut Value, version: u32) {
+    if let Value::Object(ref mut map) = value {
+        map.insert("schema_version".to_string(), Value::from(version));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrate_work_v1_to_v2_adds_phase_and_reaped_at() {
+        let v1 = json!({"work_id": "00000000-0000-0000-0000-000000000000"});
+
+        let migrated = migrate_work(v1).unwrap();
+
+        assert_eq!(migrated["phase"], json!("Requested"));
+        assert_eq!(migrated["reaped_at"], Value::Null);
+        assert_eq!(migrated["schema_version"], json!(WORK_SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_work_v1_to_v2_does_not_clobber_existing_fields() {
+        let v1 = json!({"work_id": "00000000-0000-0000-0000-000000000000", "phase": "Done"});
+
+        let migrated = migrate_work(v1).unwrap();
+
+        assert_eq!(migrated["phase"], json!("Done"));
+    }
+
+    #[test]
+    fn migrate_work_leaves_current_version_untouched() {
+        let current = json!({"work_id": "00000000-0000-0000-0000-000000000000", "phase": "Transferred", "schema_version": WORK_SCHEMA_VERSION});
+
+        let migrated = migrate_work(current).unwrap();
+
+        assert_eq!(migrated["phase"], json!("Transferred"));
+        assert_eq!(migrated["schema_version"], json!(WORK_SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_work_rejects_future_schema_version() {
+        let future = json!({"work_id": "00000000-0000-0000-0000-000000000000", "schema_version": WORK_SCHEMA_VERSION + 1});
+
+        match migrate_work(future) {
+            Err(MigrationError::TooNew { found, max_supported }) => {
+                assert_eq!(found, WORK_SCHEMA_VERSION + 1);
+                assert_eq!(max_supported, WORK_SCHEMA_VERSION);
+            },
+            other => panic!("expected TooNew, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn migrate_request_sets_current_schema_version() {
+        let v1 = json!({"request_id": "00000000-0000-0000-0000-000000000000"});
+
+        let migrated = migrate_request(v1).unwrap();
+
+        assert_eq!(migrated["schema_version"], json!(REQUEST_SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn migrate_request_rejects_future_schema_version() {
+        let future = json!({"request_id": "00000000-0000-0000-0000-000000000000", "schema_version": REQUEST_SCHEMA_VERSION + 1});
+
+        assert!(matches!(migrate_request(future), Err(MigrationError::TooNew { .. })));
+    }
+}
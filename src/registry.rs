@@ -0,0 +1,322 @@
+// registry.rs
+//
+// A persistent record of every processing run, modeled on Proxmox's
+// worker_task: each run is assigned a UPID-style identifier, appended to
+// an `active` file when it starts, and moved into an `archive` file (with
+// an end time and terminal status) when it finishes. The `archive` file
+// is only ever appended to; it is not rotated or trimmed. This
+// gives operators a queryable history of every sync without grepping
+// logs, and `reconcile_active` lets a restarted daemon notice and archive
+// entries a crashed process left behind instead of looking like
+// transfers that are still running. A third, append-only `retries` file
+// records every per-file retry attempt a task makes, so a flaky endpoint
+// shows up as a pattern instead of vanishing into the per-unit task log.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use uuid::Uuid;
+
+/// RegistryError represents a failure reading or writing the task registry.
+#[derive(Debug)]
+pub enum RegistryError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Io(e) => write!(f, "I/O error: {}", e),
+            RegistryError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        RegistryError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RegistryError {
+    fn from(e: serde_json::Error) -> Self {
+        RegistryError::Json(e)
+    }
+}
+
+/// TaskId is a UPID-style identifier: hostname, pid, start time (unix
+/// seconds) and the work_id being processed, so a task is uniquely and
+/// unambiguously identifiable even across restarts that happen to reuse a pid.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TaskId {
+    pub hostname: String,
+    pub pid: u32,
+    pub start_time: i64,
+    pub work_id: Uuid,
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UPID:{}:{:08X}:{:08X}:{}:", self.hostname, self.pid, self.start_time, self.work_id)
+    }
+}
+
+/// TaskStatus is the terminal state of a finished task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TaskStatus {
+    Ok,
+    Warning,
+    Error,
+    Quarantined,
+    /// the process that owned this task died without recording an end time
+    Interrupted,
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            TaskStatus::Ok => "OK",
+            TaskStatus::Warning => "WARNING",
+            TaskStatus::Error => "ERROR",
+            TaskStatus::Quarantined => "QUARANTINED",
+            TaskStatus::Interrupted => "INTERRUPTED",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// ActiveTaskRecord is one line of the `active` file: a task that has
+/// started but not yet finished.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ActiveTaskRecord {
+    pub id: TaskId,
+    pub request_id: Uuid,
+    pub tape: String,
+    pub started_at: DateTime<Utc>,
+}
+
+/// ArchivedTaskRecord is one line of the `archive` (a.k.a. `index`) file: a
+/// task that has finished, successfully or not.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ArchivedTaskRecord {
+    pub id: TaskId,
+    pub request_id: Uuid,
+    pub tape: String,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub status: TaskStatus,
+}
+
+/// RetryRecord is one line of the `retries` file: a single retry attempt
+/// for one file within a task, so operators can see which endpoints/files
+/// are flaky without grepping logs.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RetryRecord {
+    pub id: TaskId,
+    pub file_name: String,
+    pub attempt: u32,
+    pub error: String,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Guards every read-all/rewrite-all of the `active` file. `start_task`'s
+/// append is already atomic on its own, but `finish_task` and
+/// `reconcile_active` both read the whole file, compute a new version, and
+/// rewrite it wholesale -- two callers doing that concurrently (e.g.
+/// `globus_xfer`'s worker pool finishing two tasks around the same time)
+/// can each work from the same pre-removal snapshot, and whichever rewrite
+/// lands second silently resurrects the other's already-finished entry.
+/// This only protects against concurrent threads within one process; it
+/// does not take an OS-level file lock, since the registry is only ever
+/// written by one process's worker pool at a time.
+fn active_file_lock() -> &'static Mutex<()> {
+    static LOCK: OnceLock<Mutex<()>> = OnceLock::new();
+    LOCK.get_or_init(|| Mutex::new(()))
+}
+
+fn active_path(registry_dir: &Path) -> PathBuf {
+    registry_dir.join("active")
+}
+
+fn archive_path(registry_dir: &Path) -> PathBuf {
+    registry_dir.join("archive")
+}
+
+fn retries_path(registry_dir: &Path) -> PathBuf {
+    registry_dir.join("retries")
+}
+
+/// Append `record` as one JSON-per-line entry to `path`, creating its
+/// parent directory if needed.
+fn append_line<T: Serialize>(path: &Path, record: &T) -> Result<(), RegistryError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// Read every JSON-per-line record in `path`, or an empty vector if it
+/// doesn't exist yet.
+fn read_lines<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Vec<T>, RegistryError> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let reader = BufReader::new(fs::File::open(path)?);
+    let mut records = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        records.push(serde_json::from_str(&line)?);
+    }
+    Ok(records)
+}
+
+/// Atomically rewrite `path` to contain exactly `records`, one JSON object
+/// per line, so a reader never observes a half-written file.
+fn rewrite_lines<T: Serialize>(path: &Path, records: &[T]) -> Result<(), RegistryError> {
+    let mut contents = String::new();
+    for record in records {
+        contents.push_str(&serde_json::to_string(record)?);
+        contents.push('\n');
+    }
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// The HOSTNAME env var, which is set in the container images this
+/// pipeline runs in; falls back to "unknown" rather than failing a task
+/// registration over a missing hostname.
+fn hostname() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Build a UPID-style `TaskId` for the current process, identifying
+/// `work_id` as the unit of work it is about to process.
+pub fn task_id_for(work_id: Uuid) -> TaskId {
+    TaskId {
+        hostname: hostname(),
+        pid: std::process::id(),
+        start_time: Utc::now().timestamp(),
+        work_id,
+    }
+}
+
+/// Record that processing of `work_id`/`request_id`/`tape` has started,
+/// appending an `ActiveTaskRecord` to `registry_dir`'s `active` file.
+pub fn start_task(registry_dir: &Path, work_id: Uuid, request_id: Uuid, tape: &str) -> Result<ActiveTaskRecord, RegistryError> {
+    let record = ActiveTaskRecord {
+        id: task_id_for(work_id),
+        request_id,
+        tape: tape.to_string(),
+        started_at: Utc::now(),
+    };
+    append_line(&active_path(registry_dir), &record)?;
+    Ok(record)
+}
+
+/// Record that `active` has finished with `status`: remove it from the
+/// `active` file and append an `ArchivedTaskRecord` for it to the `archive` file.
+pub fn finish_task(registry_dir: &Path, active: &ActiveTaskRecord, status: TaskStatus) -> Result<(), RegistryError> {
+    let path = active_path(registry_dir);
+    {
+        let _guard = active_file_lock().lock().unwrap_or_else(|e| e.into_inner());
+        let remaining: Vec<ActiveTaskRecord> = read_lines::<ActiveTaskRecord>(&path)?.into_iter().filter(|r| r.id != active.id).collect();
+        rewrite_lines(&path, &remaining)?;
+    }
+
+    append_line(&archive_path(registry_dir), &ArchivedTaskRecord {
+        id: active.id.clone(),
+        request_id: active.request_id,
+        tape: active.tape.clone(),
+        started_at: active.started_at,
+        ended_at: Utc::now(),
+        status,
+    })
+}
+
+/// Record one retry attempt for `file_name` within the task `id`, appending
+/// to `registry_dir`'s `retries` file.
+pub fn record_retry(registry_dir: &Path, id: &TaskId, file_name: &str, attempt: u32, error: &str) -> Result<(), RegistryError> {
+    append_line(&retries_path(registry_dir), &RetryRecord {
+        id: id.clone(),
+        file_name: file_name.to_string(),
+        attempt,
+        error: error.to_string(),
+        recorded_at: Utc::now(),
+    })
+}
+
+/// List every task currently recorded as active.
+pub fn list_active(registry_dir: &Path) -> Result<Vec<ActiveTaskRecord>, RegistryError> {
+    read_lines(&active_path(registry_dir))
+}
+
+/// Find the active record for `work_id`, if one is already recorded --
+/// e.g. a multi-cycle transfer that called `start_task` on a previous poll
+/// and is now being processed again before it reaches a terminal state.
+pub fn find_active(registry_dir: &Path, work_id: Uuid) -> Result<Option<ActiveTaskRecord>, RegistryError> {
+    Ok(list_active(registry_dir)?.into_iter().find(|r| r.id.work_id == work_id))
+}
+
+/// List the most recently archived tasks, oldest first, up to `limit`.
+pub fn list_archive(registry_dir: &Path, limit: usize) -> Result<Vec<ArchivedTaskRecord>, RegistryError> {
+    let mut records: Vec<ArchivedTaskRecord> = read_lines(&archive_path(registry_dir))?;
+    if records.len() > limit {
+        records = records.split_off(records.len() - limit);
+    }
+    Ok(records)
+}
+
+/// Reconcile the `active` file on startup: any entry whose pid is no
+/// longer alive is presumed to belong to a crashed process, and is moved
+/// into the archive with `TaskStatus::Interrupted` rather than left
+/// looking like a transfer that is still running. Returns how many
+/// entries were reconciled this way.
+pub fn reconcile_active(registry_dir: &Path) -> Result<usize, RegistryError> {
+    let path = active_path(registry_dir);
+    let dead: Vec<ActiveTaskRecord> = {
+        let _guard = active_file_lock().lock().unwrap_or_else(|e| e.into_inner());
+        let records: Vec<ActiveTaskRecord> = read_lines(&path)?;
+        let (alive, dead): (Vec<_>, Vec<_>) = records.into_iter().partition(|r| pid_is_alive(r.id.pid));
+        rewrite_lines(&path, &alive)?;
+        dead
+    };
+
+    for record in &dead {
+        append_line(&archive_path(registry_dir), &ArchivedTaskRecord {
+            id: record.id.clone(),
+            request_id: record.request_id,
+            tape: record.tape.clone(),
+            started_at: record.started_at,
+            ended_at: Utc::now(),
+            status: TaskStatus::Interrupted,
+        })?;
+    }
+
+    Ok(dead.len())
+}
+
+#[cfg(target_os = "linux")]
+fn pid_is_alive(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pid_is_alive(_pid: u32) -> bool {
+    // best-effort only; without /proc we can't check liveness, so assume
+    // alive and leave cleanup to an operator
+    true
+}
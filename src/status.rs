@@ -0,0 +1,186 @@
+// status.rs
+//
+// Per-request progress records, so an operator can poll how far a
+// TaccSyncRequest has gotten without grepping logs. Each request gets one
+// JSON file in STATUS_DIR, named by its request_id, rewritten atomically in
+// place every time the syncer or finisher learns something new about it.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::fileutil::FileUtilError;
+
+/// StatusError represents a failure reading or writing a request's status file.
+#[derive(Debug)]
+pub enum StatusError {
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatusError::Io(e) => write!(f, "I/O error: {}", e),
+            StatusError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+impl From<io::Error> for StatusError {
+    fn from(e: io::Error) -> Self {
+        StatusError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StatusError {
+    fn from(e: serde_json::Error) -> Self {
+        StatusError::Json(e)
+    }
+}
+
+impl From<FileUtilError> for StatusError {
+    fn from(e: FileUtilError) -> Self {
+        match e {
+            FileUtilError::Io(e) => StatusError::Io(e),
+            FileUtilError::Json(e) => StatusError::Json(e),
+        }
+    }
+}
+
+/// RequestPhase is where a TaccSyncRequest currently is in the
+/// syncer/finisher pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RequestPhase {
+    /// the syncer is listing every file under HSI_BASE_PATH
+    QueryingHsi,
+    /// the syncer is filtering that listing down to the request's pattern
+    Filtering,
+    /// the syncer is querying hsi for tape location metadata
+    QueryingMetadata,
+    /// the syncer is sorting and grouping matched files by tape
+    Grouping,
+    /// the syncer is writing TaccSyncWork units to the work directory
+    GeneratingUnits,
+    /// work units have been handed off; the finisher is waiting for them
+    /// to clear the hpss/globus/reaper directories
+    InFlight,
+    /// every work unit this request generated has cleared the pipeline
+    Finished,
+}
+
+/// RequestStatus is the full progress record for one TaccSyncRequest,
+/// written to `<STATUS_DIR>/<request_id>.json`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RequestStatus {
+    pub request_id: Uuid,
+    pub phase: RequestPhase,
+    /// how many hsi-listed files matched the request's pattern
+    #[serde(default)]
+    pub matched_files: usize,
+    /// the total size, in bytes, of the matched files
+    #[serde(default)]
+    pub total_bytes: u64,
+    /// how many tape groups the syncer grouped the matched files into; a
+    /// group may be split into several work units
+    #[serde(default)]
+    pub tape_groups: usize,
+    /// work units for this request still present in the hpss directory
+    #[serde(default)]
+    pub hpss_in_flight: usize,
+    /// work units for this request still present in the globus directory
+    #[serde(default)]
+    pub globus_in_flight: usize,
+    /// work units for this request still present in the reaper directory
+    #[serde(default)]
+    pub reaper_in_flight: usize,
+    pub updated_at: DateTime<Utc>,
+}
+
+fn status_path(status_dir: &Path, request_id: Uuid) -> PathBuf {
+    status_dir.join(format!("{}.json", request_id))
+}
+
+/// Read the current status record for `request_id`, or a fresh default one
+/// (in `QueryingHsi` phase) if none has been written yet.
+fn read_or_default(status_dir: &Path, request_id: Uuid) -> Result<RequestStatus, StatusError> {
+    let path = status_path(status_dir, request_id);
+    if !path.exists() {
+        return Ok(RequestStatus {
+            request_id,
+            phase: RequestPhase::QueryingHsi,
+            matched_files: 0,
+            total_bytes: 0,
+            tape_groups: 0,
+            hpss_in_flight: 0,
+            globus_in_flight: 0,
+            reaper_in_flight: 0,
+            updated_at: Utc::now(),
+        });
+    }
+    let file = File::open(&path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Move `request_id`'s status record into `phase`, leaving every other
+/// field as it was.
+pub fn set_phase(status_dir: &Path, request_id: Uuid, phase: RequestPhase) -> Result<(), StatusError> {
+    let mut status = read_or_default(status_dir, request_id)?;
+    status.phase = phase;
+    status.updated_at = Utc::now();
+    crate::atomic_write_json(&status, &status_path(status_dir, request_id))?;
+    Ok(())
+}
+
+/// Record how many files matched the request's pattern and their combined
+/// size, without changing the phase.
+pub fn set_matched_files(status_dir: &Path, request_id: Uuid, matched_files: usize, total_bytes: u64) -> Result<(), StatusError> {
+    let mut status = read_or_default(status_dir, request_id)?;
+    status.matched_files = matched_files;
+    status.total_bytes = total_bytes;
+    status.updated_at = Utc::now();
+    crate::atomic_write_json(&status, &status_path(status_dir, request_id))?;
+    Ok(())
+}
+
+/// Record how many tape groups the syncer grouped the matched files into,
+/// without changing the phase.
+pub fn set_tape_groups(status_dir: &Path, request_id: Uuid, tape_groups: usize) -> Result<(), StatusError> {
+    let mut status = read_or_default(status_dir, request_id)?;
+    status.tape_groups = tape_groups;
+    status.updated_at = Utc::now();
+    crate::atomic_write_json(&status, &status_path(status_dir, request_id))?;
+    Ok(())
+}
+
+/// Record how many of this request's work units the finisher still finds
+/// in the hpss/globus/reaper directories, moving the phase to `InFlight`.
+pub fn set_in_flight(status_dir: &Path, request_id: Uuid, hpss_in_flight: usize, globus_in_flight: usize, reaper_in_flight: usize) -> Result<(), StatusError> {
+    let mut status = read_or_default(status_dir, request_id)?;
+    status.phase = RequestPhase::InFlight;
+    status.hpss_in_flight = hpss_in_flight;
+    status.globus_in_flight = globus_in_flight;
+    status.reaper_in_flight = reaper_in_flight;
+    status.updated_at = Utc::now();
+    crate::atomic_write_json(&status, &status_path(status_dir, request_id))?;
+    Ok(())
+}
+
+/// Mark `request_id`'s status record `Finished`, with every in-flight count
+/// reset to zero.
+pub fn finish(status_dir: &Path, request_id: Uuid) -> Result<(), StatusError> {
+    let mut status = read_or_default(status_dir, request_id)?;
+    status.phase = RequestPhase::Finished;
+    status.hpss_in_flight = 0;
+    status.globus_in_flight = 0;
+    status.reaper_in_flight = 0;
+    status.updated_at = Utc::now();
+    crate::atomic_write_json(&status, &status_path(status_dir, request_id))?;
+    Ok(())
+}
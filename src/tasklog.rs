@@ -0,0 +1,138 @@
+// tasklog.rs
+//
+// A tracing layer that mirrors every event emitted while processing a
+// work unit into a dedicated log file for that unit, in addition to
+// whatever other layers (stderr, syslog) are installed. This gives
+// operators one self-contained log file per work unit that can travel
+// with its JSON to the outbox/quarantine for post-mortem debugging,
+// instead of many work units' log lines interleaving in one stream.
+
+use std::cell::{Cell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::field::{Field, Visit};
+use tracing::{error, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::Layer;
+
+use crate::durable_move;
+
+thread_local! {
+    static ACTIVE_TASK_LOG: RefCell<Option<File>> = RefCell::new(None);
+    static WARNING_COUNT: Cell<u64> = Cell::new(0);
+}
+
+/// TaskLogGuard installs a per-work-unit log file as this thread's active
+/// task log for as long as it stays alive; dropping it restores whatever
+/// was active before (normally nothing, since stages process one work
+/// unit per thread at a time). It also resets the thread's WARN/ERROR
+/// counter on open and restores the previous one on drop, so a caller can
+/// ask `warning_count()` to find out whether the unit it just processed
+/// logged any warnings or errors along the way.
+pub struct TaskLogGuard {
+    previous: Option<File>,
+    previous_warning_count: u64,
+}
+
+impl TaskLogGuard {
+    /// Open (creating `log_dir` if needed) `log_dir/<name>.log` in append
+    /// mode and install it as this thread's active task log.
+    ///
+    /// # Arguments
+    ///
+    /// * `log_dir` - the directory per-task log files are written into
+    /// * `name` - the task's identifying name, e.g. a `work_id`
+    pub fn open(log_dir: &Path, name: &str) -> std::io::Result<Self> {
+        std::fs::create_dir_all(log_dir)?;
+        let path = log_dir.join(format!("{}.log", name));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let previous = ACTIVE_TASK_LOG.with(|cell| cell.borrow_mut().replace(file));
+        let previous_warning_count = WARNING_COUNT.with(|c| c.replace(0));
+        Ok(TaskLogGuard { previous, previous_warning_count })
+    }
+
+    /// The path a task log for `name` in `log_dir` would be opened at,
+    /// whether or not a guard for it is currently active. Used by callers
+    /// that want to move the finished log file alongside its work unit.
+    pub fn path_for(log_dir: &Path, name: &str) -> PathBuf {
+        log_dir.join(format!("{}.log", name))
+    }
+
+    /// Move a work unit's per-task log file, if one was written, alongside
+    /// its JSON into `dest_dir` so it travels with the unit for post-mortem
+    /// debugging.
+    pub fn move_to(log_dir: &Path, name: &str, dest_dir: &Path) {
+        let log_path = Self::path_for(log_dir, name);
+        if log_path.exists() {
+            if let Err(e) = durable_move(&log_path, dest_dir) {
+                error!("Unable to move task log {} to {}: {}", log_path.display(), dest_dir.display(), e);
+            }
+        }
+    }
+
+    /// How many WARN/ERROR-level events have been logged since this guard
+    /// was opened.
+    pub fn warning_count(&self) -> u64 {
+        WARNING_COUNT.with(|c| c.get())
+    }
+}
+
+/// How many WARN/ERROR-level events have been logged on this thread since
+/// the currently active `TaskLogGuard` was opened, if any. `daemon::process_one`
+/// opens the guard for a work unit before handing it to a `Worker`, so a
+/// `Worker` implementation that needs to know whether the unit it's finishing
+/// up logged any warnings can call this instead of threading the guard itself
+/// through `Worker::process`.
+pub fn warning_count() -> u64 {
+    WARNING_COUNT.with(|c| c.get())
+}
+
+impl Drop for TaskLogGuard {
+    fn drop(&mut self) {
+        ACTIVE_TASK_LOG.with(|cell| {
+            *cell.borrow_mut() = self.previous.take();
+        });
+        WARNING_COUNT.with(|c| c.set(self.previous_warning_count));
+    }
+}
+
+/// TaskLogLayer writes every event to the thread's active `TaskLogGuard`
+/// file, if one is installed; events on threads with no active task log
+/// (or that occur outside any work-unit span) are left to the other
+/// layers (e.g. stderr/syslog) to handle. WARN/ERROR events are also
+/// tallied into the thread's warning counter.
+pub struct TaskLogLayer;
+
+impl<S: Subscriber> Layer<S> for TaskLogLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        ACTIVE_TASK_LOG.with(|cell| {
+            if let Some(file) = cell.borrow_mut().as_mut() {
+                if matches!(*event.metadata().level(), Level::WARN | Level::ERROR) {
+                    WARNING_COUNT.with(|c| c.set(c.get() + 1));
+                }
+
+                let mut message = MessageVisitor::default();
+                event.record(&mut message);
+                let _ = writeln!(
+                    file,
+                    "{} {} {}",
+                    chrono::Utc::now().to_rfc3339(),
+                    event.metadata().level(),
+                    message.0
+                );
+            }
+        });
+    }
+}
+
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
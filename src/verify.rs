@@ -0,0 +1,227 @@
+// verify.rs
+//
+// Before the reaper destroys a transfer buffer directory, prove the files
+// that landed there are actually intact -- right size, and (when a
+// checksum was recorded) the right content -- rather than trusting a
+// Globus/hsi exit code alone.
+
+use crate::TaccSyncWork;
+use blake3::Hasher;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// how much of a file to read into memory at a time while hashing it
+const HASH_BUFFER_SIZE: usize = 8 * 1024 * 1024;
+
+/// VerifyError describes why a work unit's files failed post-transfer
+/// verification.
+#[derive(Debug)]
+pub enum VerifyError {
+    Missing(PathBuf),
+    SizeMismatch { path: PathBuf, expected: u64, actual: u64 },
+    ChecksumMismatch { path: PathBuf, expected: String, actual: String },
+    Io(PathBuf, io::Error),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Missing(path) => write!(f, "{} is missing", path.display()),
+            VerifyError::SizeMismatch { path, expected, actual } => {
+                write!(f, "{} has size {} but expected {}", path.display(), actual, expected)
+            },
+            VerifyError::ChecksumMismatch { path, expected, actual } => {
+                write!(f, "{} has checksum {} but expected {}", path.display(), actual, expected)
+            },
+            VerifyError::Io(path, e) => write!(f, "unable to read {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl VerifyError {
+    /// The file this verification failure is about, for quarantine/logging.
+    pub fn path(&self) -> &Path {
+        match self {
+            VerifyError::Missing(path) => path,
+            VerifyError::SizeMismatch { path, .. } => path,
+            VerifyError::ChecksumMismatch { path, .. } => path,
+            VerifyError::Io(path, _) => path,
+        }
+    }
+}
+
+/// Verify that every file in `work` landed intact under
+/// `transfer_dir/<work_id>/`: it must exist, its size must match the
+/// recorded `size`, and -- when `checksum` is present -- its BLAKE3 digest
+/// must match.
+///
+/// # Arguments
+///
+/// * `work` - the work unit whose files should be verified
+/// * `transfer_dir` - the root of the transfer buffer
+pub fn verify_work(work: &TaccSyncWork, transfer_dir: &Path) -> Result<(), VerifyError> {
+    let work_dir = transfer_dir.join(work.work_id.to_string());
+
+    for file in &work.files {
+        let path = work_dir.join(&file.file_name);
+
+        let metadata = std::fs::metadata(&path).map_err(|_| VerifyError::Missing(path.clone()))?;
+        if metadata.len() != file.size {
+            return Err(VerifyError::SizeMismatch { path, expected: file.size, actual: metadata.len() });
+        }
+
+        if let Some(expected) = &file.checksum {
+            let actual = hash_file(&path).map_err(|e| VerifyError::Io(path.clone(), e))?;
+            if &actual != expected {
+                return Err(VerifyError::ChecksumMismatch { path, expected: expected.clone(), actual });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Stream `path` through a BLAKE3 hasher in `HASH_BUFFER_SIZE` chunks and
+/// return its hex-encoded digest.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{TaccSyncFile, TaccSyncWork, WorkPhase};
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    /// A scratch directory under the system temp dir, unique per test run
+    /// and removed on drop, since this repo has no tempfile dependency.
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new() -> Self {
+            let path = std::env::temp_dir().join(format!("tacc-sync-verify-test-{}", Uuid::new_v4()));
+            std::fs::create_dir_all(&path).expect("unable to create scratch dir");
+            ScratchDir(path)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn work_with_file(work_id: Uuid, file_name: &str, size: u64, checksum: Option<String>) -> TaccSyncWork {
+        TaccSyncWork {
+            work_id,
+            date_created: Utc::now(),
+            tape: "TAPE001".to_string(),
+            size,
+            request_id: Uuid::new_v4(),
+            files: vec![TaccSyncFile {
+                file_name: file_name.to_string(),
+                hpss_path: format!("/hpss/{}", file_name),
+                size,
+                tape_num: 1,
+                tape_offset: 0,
+                checksum,
+                attempt_count: 0,
+                last_error: None,
+                retry_after: None,
+                globus_task_id: None,
+            }],
+            transfer_id: None,
+            phase: WorkPhase::Transferred,
+            reaped_at: None,
+            schema_version: crate::WORK_SCHEMA_VERSION,
+        }
+    }
+
+    #[test]
+    fn hash_file_is_deterministic_and_content_sensitive() {
+        let scratch = ScratchDir::new();
+        let path_a = scratch.0.join("a.bin");
+        let path_b = scratch.0.join("b.bin");
+        std::fs::write(&path_a, b"hello world").unwrap();
+        std::fs::write(&path_b, b"goodbye world").unwrap();
+
+        let digest_a1 = hash_file(&path_a).unwrap();
+        let digest_a2 = hash_file(&path_a).unwrap();
+        let digest_b = hash_file(&path_b).unwrap();
+
+        assert_eq!(digest_a1, digest_a2);
+        assert_ne!(digest_a1, digest_b);
+        assert_eq!(digest_a1.len(), 64); // hex-encoded 32-byte digest
+    }
+
+    #[test]
+    fn verify_work_passes_when_size_and_checksum_match() {
+        let scratch = ScratchDir::new();
+        let work_dir_contents = b"hello world";
+        let expected_checksum = {
+            let path = scratch.0.join("precompute.bin");
+            std::fs::write(&path, work_dir_contents).unwrap();
+            hash_file(&path).unwrap()
+        };
+
+        let work = work_with_file(Uuid::new_v4(), "file.dat", work_dir_contents.len() as u64, Some(expected_checksum));
+        let work_dir = scratch.0.join(work.work_id.to_string());
+        std::fs::create_dir_all(&work_dir).unwrap();
+        std::fs::write(work_dir.join("file.dat"), work_dir_contents).unwrap();
+
+        assert!(verify_work(&work, &scratch.0).is_ok());
+    }
+
+    #[test]
+    fn verify_work_fails_on_size_mismatch() {
+        let scratch = ScratchDir::new();
+        let work = work_with_file(Uuid::new_v4(), "file.dat", 999, None);
+        let work_dir = scratch.0.join(work.work_id.to_string());
+        std::fs::create_dir_all(&work_dir).unwrap();
+        std::fs::write(work_dir.join("file.dat"), b"hello world").unwrap();
+
+        match verify_work(&work, &scratch.0) {
+            Err(VerifyError::SizeMismatch { expected, actual, .. }) => {
+                assert_eq!(expected, 999);
+                assert_eq!(actual, 11);
+            },
+            other => panic!("expected SizeMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn verify_work_fails_on_checksum_mismatch() {
+        let scratch = ScratchDir::new();
+        let work = work_with_file(Uuid::new_v4(), "file.dat", 11, Some("0".repeat(64)));
+        let work_dir = scratch.0.join(work.work_id.to_string());
+        std::fs::create_dir_all(&work_dir).unwrap();
+        std::fs::write(work_dir.join("file.dat"), b"hello world").unwrap();
+
+        assert!(matches!(verify_work(&work, &scratch.0), Err(VerifyError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn verify_work_fails_when_file_is_missing() {
+        let scratch = ScratchDir::new();
+        let work = work_with_file(Uuid::new_v4(), "file.dat", 11, None);
+
+        assert!(matches!(verify_work(&work, &scratch.0), Err(VerifyError::Missing(_))));
+    }
+}